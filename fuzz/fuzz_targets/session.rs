@@ -0,0 +1,91 @@
+// Feeds random bytes through a whole session, from the client flags of the handshake to the
+// transmission phase, against an in-memory export. Run with `cargo fuzz run session`.
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use std::{
+    io::{self, Error, ErrorKind, Write},
+    net::Shutdown,
+    os::unix::net::UnixStream,
+    sync::{Arc, RwLock},
+    thread,
+};
+
+use nbd_rs::{
+    block::BlockStorage,
+    nbd::{ExportOptions, NBDExport, NBDServer, NBDSocket},
+    util::Propagation,
+};
+
+const EXPORT_SIZE: usize = 1024 * 1024;
+
+struct MemoryBlock {
+    data: Vec<u8>,
+}
+
+impl MemoryBlock {
+    fn range(&self, offset: u64, length: usize) -> Result<std::ops::Range<usize>, Error> {
+        match offset.checked_add(length as u64) {
+            Some(end) if end <= self.data.len() as u64 => Ok(offset as usize..end as usize),
+            _ => Err(Error::new(ErrorKind::InvalidInput, "Out of bounds")),
+        }
+    }
+}
+
+impl BlockStorage for MemoryBlock {
+    fn init(&mut self, _init_volume: bool) -> Result<(), Box<dyn std::error::Error>> { Ok(()) }
+    fn init_volume(&mut self) -> Result<(), Box<dyn std::error::Error>> { Ok(()) }
+    fn check_volume(&mut self) -> Result<(), Box<dyn std::error::Error>> { Ok(()) }
+    fn destroy_volume(&mut self) {}
+    fn get_name(&self) -> String { String::from("memory") }
+    fn get_volume_size(&self) -> u64 { self.data.len() as u64 }
+
+    fn read(&self, offset: u64, length: usize) -> Result<Vec<u8>, Error> {
+        Ok(self.data[self.range(offset, length)?].to_vec())
+    }
+
+    fn write(&mut self, offset: u64, length: usize, data: &[u8]) -> Result<Propagation, Error> {
+        let range = self.range(offset, length)?;
+        if data.len() < length {
+            return Err(Error::new(ErrorKind::InvalidInput, "Short write"))
+        }
+        self.data[range].copy_from_slice(&data[..length]);
+        Ok(Propagation::Guaranteed)
+    }
+
+    fn flush(&self, _offset: u64, _length: usize) -> Result<Propagation, Error> {
+        Ok(Propagation::Guaranteed)
+    }
+
+    fn close(&mut self) {}
+}
+
+fuzz_target!(init: {
+    // the session may hang up before the whole input is sent
+    unsafe { libc::signal(libc::SIGPIPE, libc::SIG_IGN) };
+}, |input: &[u8]| {
+    let driver = Box::new(MemoryBlock { data: vec![0; EXPORT_SIZE] });
+    let export = NBDExport::with_driver(String::from("default"), driver, ExportOptions::default());
+    let exports = Arc::new(RwLock::new(vec![Arc::new(RwLock::new(export))]));
+
+    let (server, client) = UnixStream::pair().unwrap();
+    let mut client_writer = client.try_clone().unwrap();
+    let mut client_reader = client;
+
+    // the client side sends the input, and discards whatever the server replies
+    let input = input.to_vec();
+    // both fail once the session hung up, which is fine
+    let writer = thread::spawn(move || {
+        let _ = client_writer.write_all(&input).and_then(|_| client_writer.shutdown(Shutdown::Write));
+    });
+    let reader = thread::spawn(move || {
+        let _ = io::copy(&mut client_reader, &mut io::sink());
+    });
+
+    if let Ok(session) = NBDServer::handle_connection(NBDSocket::Unix(server), exports, None) {
+        session.handle();
+    }
+    writer.join().unwrap();
+    reader.join().unwrap();
+});
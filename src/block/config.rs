@@ -13,6 +13,7 @@ pub struct BlockStorageConfig {
     pub driver: String,
     pub conn_str: String,
     pub init_volume: bool,
+    pub read_only: bool,
 }
 
 pub fn block_storage_with_config(config: BlockStorageConfig) -> Result<Box<dyn BlockStorage>, Error> {
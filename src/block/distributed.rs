@@ -7,9 +7,9 @@ use log;
 
 use crate::{
     object::{ObjectStorage, object_storages_with_config},
-    block::{BlockStorage, BlockStorageConfig, ShardDistribution},
+    block::{BlockStorage, BlockStorageConfig, ShardDistribution, delete_shard},
 };
-use crate::util::Propagation;
+use crate::util::{Propagation, Extent, AlignedBlockIter, push_extent};
 
 // Driver: DistributedBlock
 
@@ -45,7 +45,7 @@ impl DistributedBlock {
         let replicas: u8 = get_cfg_entry(&split, "replicas").unwrap().parse().unwrap();
         let backends = get_cfg_entry(&split, "backends").unwrap();
 
-        let object_storages = object_storages_with_config(backends).unwrap();
+        let object_storages = object_storages_with_config(backends, config.read_only).unwrap();
         let shard_distribution = ShardDistribution::new(object_storages.len() as u8, replicas);
 
         let mut distributed_block = DistributedBlock {
@@ -142,8 +142,8 @@ impl BlockStorage for DistributedBlock {
 
         for (i, storage) in self.object_storages.iter().enumerate() {
             let size_str = volume_size.to_string();
-            storage.write(String::from("size"), &size_str.as_bytes());
-            storage.persist_object(String::from("size"));
+            storage.write(String::from("size"), &size_str.as_bytes())?;
+            storage.persist_object(String::from("size"))?;
             log::info!("Volume size written to: node-{}", i);
         }
 
@@ -240,7 +240,10 @@ impl BlockStorage for DistributedBlock {
                     continue;
                 }
                 if i == end {
-                    let read_size = ((length as u64 + offset % self.shard_size) % self.shard_size) as usize;
+                    let mut read_size = ((length as u64 + offset % self.shard_size) % self.shard_size) as usize;
+                    if read_size == 0 {
+                        read_size = self.shard_size as usize;
+                    }
                     buffer.extend_from_slice(&vec![0_u8; read_size]);
                     break;
                 }
@@ -322,12 +325,9 @@ impl BlockStorage for DistributedBlock {
         Ok(overall_first_propagation)
     }
 
-    fn flush(&mut self, offset: u64, length: usize) -> Result<Propagation, Error> {
-        // FIXME! 
-        // This is so wrong.
-        // We are trying to flush same data more than once but flush function returns propagation which depends only
-        // 1 node so for temporary, propagation of the first node is returned.
-        let mut overall_first_propagation = Propagation::Noop;
+    fn flush(&self, offset: u64, length: usize) -> Result<Propagation, Error> {
+        // Every replica has to be persisted, so the weakest propagation among them is returned
+        let mut overall_propagation : Propagation = Propagation::Guaranteed;
         for replica_idx in 0..self.shard_distribution.replicas {
             let start = self.shard_index(offset);
             let end = if 0 == (offset + length as u64) % self.shard_size {
@@ -337,7 +337,6 @@ impl BlockStorage for DistributedBlock {
             };
 
             log::debug!("storage::flush(start: {}, end: {})", start, end);
-            let mut overall_propagation : Propagation = Propagation::Guaranteed;
             for i in start..=end {
                 let shard_name = self.shard_name(i, replica_idx);
                 let propagated = self.get_object_storage(i, replica_idx).persist_object(shard_name.clone())?;
@@ -350,11 +349,86 @@ impl BlockStorage for DistributedBlock {
                     overall_propagation = propagated;
                 }
             }
-            if replica_idx == 0 {
-                overall_first_propagation = overall_propagation;
+        }
+        Ok(overall_propagation)
+    }
+
+    fn resize(&mut self, new_size: u64) -> Result<(), Error> {
+        if new_size == 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "Volume size can not be zero"));
+        }
+
+        // the volume can't be opened without its size, so it is never deleted or partially written
+        for (i, storage) in self.object_storages.iter().enumerate() {
+            let size_str = new_size.to_string();
+            storage.replace(String::from("size"), size_str.as_bytes())?;
+            storage.persist_object(String::from("size"))?;
+            log::info!("Volume size written to: node-{}", i);
+        }
+
+        if new_size < self.volume_size {
+            // zero the tail of the new last shard, so growing again exposes zeroes
+            let last_shard = self.shard_index(new_size - 1);
+            let tail_offset = new_size % self.shard_size;
+            for replica_idx in 0..self.shard_distribution.replicas {
+                let shard_name = self.shard_name(last_shard, replica_idx);
+                let storage = self.get_object_storage(last_shard, replica_idx);
+                if tail_offset > 0 && storage.exists(shard_name.clone())? {
+                    let tail_size = (self.shard_size - tail_offset) as usize;
+                    storage.partial_write(shard_name, tail_offset, tail_size, &vec![0_u8; tail_size])?;
+                }
+                for i in (last_shard + 1)..=self.shard_index(self.volume_size - 1) {
+                    let shard_name = self.shard_name(i, replica_idx);
+                    let storage = self.get_object_storage(i, replica_idx);
+                    if storage.exists(shard_name.clone())? {
+                        storage.delete(shard_name)?;
+                    }
+                }
             }
         }
-        Ok(overall_first_propagation)
+
+        log::info!("Volume is resized from {} to {}", self.volume_size, new_size);
+        self.volume_size = new_size;
+        Ok(())
+    }
+
+    fn block_status(&self, offset: u64, length: usize) -> Result<Vec<Extent>, Error> {
+        let mut extents: Vec<Extent> = Vec::new();
+        for r in (AlignedBlockIter{ from: offset as usize, to: offset as usize + length, blksize: self.shard_size as usize }) {
+            let extent_len = (r.end - r.start) as u64;
+            if self.get_replica_idx_from_shard(self.shard_index(r.start as u64))?.is_some() {
+                push_extent(&mut extents, Extent::data(extent_len));
+            } else {
+                push_extent(&mut extents, Extent::hole(extent_len));
+            }
+        }
+        Ok(extents)
+    }
+
+    fn prefetch(&self, offset: u64, length: usize) -> Result<(), Error> {
+        let start = self.shard_index(offset);
+        let end = if 0 == (offset + length as u64) % self.shard_size {
+            self.shard_index(offset + length as u64) - 1
+        } else {
+            self.shard_index(offset + length as u64)
+        };
+
+        log::debug!("storage::prefetch(start: {}, end: {})", start, end);
+        for i in start..=end {
+            // warm the same replica `read` would pick; missing shards are holes
+            let replica_idx = match self.get_replica_idx_from_shard(i)? {
+                Some(replica_idx) => replica_idx,
+                None => continue,
+            };
+            let shard_start = std::cmp::max(offset, i as u64 * self.shard_size);
+            let shard_end = std::cmp::min(offset + length as u64, (i as u64 + 1) * self.shard_size);
+            self.get_object_storage(i, replica_idx).prefetch_object(
+                self.shard_name(i, replica_idx),
+                shard_start % self.shard_size,
+                (shard_end - shard_start) as usize
+            )?;
+        }
+        Ok(())
     }
 
     fn trim(&mut self, offset: u64, length: usize) -> Result<Propagation, Error> {
@@ -371,7 +445,7 @@ impl BlockStorage for DistributedBlock {
             if i == start {
                 let trim_size = std::cmp::min((self.shard_size - offset % self.shard_size) as usize, length);
                 if trim_size as u64 % self.shard_size == 0 {
-                    overall_propagation = self.get_object_storage(i, 0).delete(object_name)?;
+                    overall_propagation = delete_shard(self.get_object_storage(i, 0).as_ref(), object_name)?;
                 } else {
                     overall_propagation = self.get_object_storage(i, 0).partial_write(
                         object_name,
@@ -383,7 +457,7 @@ impl BlockStorage for DistributedBlock {
             } else if i == end {
                 let trim_size = ((length as u64 + offset % self.shard_size) % self.shard_size) as usize;
                 if trim_size as u64 % self.shard_size == 0 {
-                    overall_propagation = self.get_object_storage(i, 0).delete(object_name)?;
+                    overall_propagation = delete_shard(self.get_object_storage(i, 0).as_ref(), object_name)?;
                 } else {
                     overall_propagation = self.get_object_storage(i, 0).partial_write(
                         object_name,
@@ -393,12 +467,18 @@ impl BlockStorage for DistributedBlock {
                     )?;
                 }
             } else {
-                overall_propagation = self.get_object_storage(i, 0).delete(object_name)?;
+                overall_propagation = delete_shard(self.get_object_storage(i, 0).as_ref(), object_name)?;
             }
         }
         Ok(overall_propagation)
     }
 
+    fn fence(&mut self) {
+        for object_storage in self.object_storages.iter() {
+            object_storage.fence();
+        }
+    }
+
     fn close(&mut self) {
         log::debug!("storage::close");
         for object_storage in self.object_storages.iter_mut(){
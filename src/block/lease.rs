@@ -0,0 +1,405 @@
+// Writer leases, so that two servers don't write to the same volume at once. Volumes on `file:`
+// storages are locked with flock(2), which is exclusive. On other object storages the lease is an object next
+// to `size`, holding the owner, its host and when it expires, which is renewed in the background
+// for as long as the lease is held.
+//
+// Object storages have no conditional writes, so two servers taking a free lease at the same time
+// are only told apart by reading the lease back after `LEASE_SETTLE`: the object lease is
+// best-effort. Once a lease is lost, the drivers of the volume are fenced, which drops the data
+// that wasn't written back yet.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{Error, ErrorKind},
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+    process,
+    sync::{Arc, Mutex, RwLock, Weak, mpsc, atomic::{AtomicBool, Ordering}},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::block::{BlockStorage, BlockStorageConfig};
+use crate::object::{ObjectStorage, object_storage_with_config};
+
+const LEASE_OBJECT: &str = "lease";
+const LEASE_DURATION: Duration = Duration::from_secs(30);
+const RENEW_INTERVAL: Duration = Duration::from_secs(10);
+// time for a competing lease write to land, before reading ours back
+const LEASE_SETTLE: Duration = Duration::from_secs(2);
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+fn hostname() -> String {
+    let mut buf = [0_u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return String::from("unknown")
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+// unique for every lease taken, even by the same process
+fn owner_id() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+    format!("{}-{:08x}", process::id(), nanos)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct LeaseRecord {
+    owner: String,
+    host: String,
+    // unix time, in seconds
+    expires: u64,
+}
+
+impl LeaseRecord {
+    fn new(owner: String) -> LeaseRecord {
+        LeaseRecord {
+            owner,
+            host: hostname(),
+            expires: now() + LEASE_DURATION.as_secs(),
+        }
+    }
+
+    fn renewed(&self) -> LeaseRecord {
+        LeaseRecord {
+            expires: now() + LEASE_DURATION.as_secs(),
+            ..self.clone()
+        }
+    }
+
+    fn expired(&self) -> bool {
+        self.expires <= now()
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        format!("owner={}\nhost={}\nexpires={}\n", self.owner, self.host, self.expires).into_bytes()
+    }
+
+    fn decode(data: &[u8]) -> Result<LeaseRecord, Error> {
+        let mut record = LeaseRecord { owner: String::new(), host: String::new(), expires: 0 };
+        for line in String::from_utf8_lossy(data).lines() {
+            match line.split_once('=') {
+                Some(("owner", owner)) => record.owner = String::from(owner),
+                Some(("host", host)) => record.host = String::from(host),
+                Some(("expires", expires)) => record.expires = expires.parse()
+                    .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid lease expiry"))?,
+                _ => {},
+            }
+        }
+        if record.owner.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, "Invalid lease object"))
+        }
+        Ok(record)
+    }
+}
+
+fn read_record(storage: &dyn ObjectStorage) -> Result<LeaseRecord, Error> {
+    LeaseRecord::decode(&storage.read(String::from(LEASE_OBJECT))?)
+}
+
+enum LeaseTarget {
+    // path of the file to lock, and whether it may be created
+    Lock(PathBuf, bool),
+    // config of the object storage to keep the lease object in
+    Object(String),
+}
+
+impl LeaseTarget {
+    fn of(config: &BlockStorageConfig) -> Result<LeaseTarget, Error> {
+        let storage = match config.driver.as_str() {
+            // raw images are always files, lock the image itself
+            "raw" => {
+                let path = config.conn_str.strip_prefix("file:").unwrap_or(&config.conn_str);
+                return Ok(LeaseTarget::Lock(PathBuf::from(path.replace("///", "/")), false))
+            },
+            "sharded" => config.conn_str.as_str(),
+            // `size` is kept on the first backend
+            "distributed" => config.conn_str
+                .split(';')
+                .find_map(|cfg| cfg.strip_prefix("backends="))
+                .and_then(|backends| backends.split(',').next())
+                .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "No backends in the driver config"))?,
+            _ => return Err(Error::new(ErrorKind::Other, "Invalid storage driver")),
+        };
+        // the lease must never sit in a cache
+        let storage = storage.strip_prefix("cache:").unwrap_or(storage);
+        match storage.strip_prefix("file:") {
+            Some(folder) => Ok(LeaseTarget::Lock(Path::new(&folder.replace("///", "/")).join(LEASE_OBJECT), true)),
+            None => Ok(LeaseTarget::Object(String::from(storage))),
+        }
+    }
+}
+
+enum Holder {
+    // the lock is released along with the file descriptor
+    Lock(#[allow(dead_code)] File),
+    Object {
+        storage: Arc<Box<dyn ObjectStorage>>,
+        owner: String,
+        // dropped to stop the renewer
+        stop: mpsc::Sender<()>,
+        renewer: thread::JoinHandle<()>,
+    },
+}
+
+type Driver = RwLock<Box<dyn BlockStorage>>;
+
+// shared with the renewer
+struct LeaseState {
+    held: AtomicBool,
+    // fenced once the lease is lost
+    drivers: Mutex<Vec<Weak<Driver>>>,
+}
+
+impl LeaseState {
+    fn new() -> Arc<LeaseState> {
+        Arc::new(LeaseState {
+            held: AtomicBool::new(true),
+            drivers: Mutex::new(Vec::new()),
+        })
+    }
+
+    fn lose(&self) {
+        let drivers = self.drivers.lock().unwrap();
+        self.held.store(false, Ordering::SeqCst);
+        for driver in drivers.iter().filter_map(Weak::upgrade) {
+            driver.write().unwrap().fence();
+        }
+    }
+}
+
+pub struct Lease {
+    name: String,
+    state: Arc<LeaseState>,
+    holder: Option<Holder>,
+}
+
+impl Lease {
+    // `steal` takes over object leases that are held by another server; file locks can't be
+    // stolen, as they are only ever held by a running process.
+    pub fn acquire(config: &BlockStorageConfig, steal: bool) -> Result<Lease, Error> {
+        let name = config.export_name.clone().unwrap_or_else(|| config.conn_str.clone());
+        match LeaseTarget::of(config)? {
+            LeaseTarget::Lock(path, create) => Lease::lock(name, &path, create),
+            LeaseTarget::Object(storage) => {
+                let storage = object_storage_with_config(storage, false)?;
+                Lease::take(name, Arc::new(storage), owner_id(), steal, LEASE_SETTLE)
+            },
+        }
+    }
+
+    fn lock(name: String, path: &Path, create: bool) -> Result<Lease, Error> {
+        let file = OpenOptions::new().read(true).write(create).create(create).open(path)?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = Error::last_os_error();
+            if err.kind() == ErrorKind::WouldBlock {
+                return Err(Error::new(
+                    ErrorKind::WouldBlock,
+                    format!("Volume of `{}` is locked by another process ({})", name, path.display()),
+                ))
+            }
+            return Err(err)
+        }
+        log::info!("Locked {} for `{}`", path.display(), name);
+        Ok(Lease {
+            name,
+            state: LeaseState::new(),
+            holder: Some(Holder::Lock(file)),
+        })
+    }
+
+    // Reads the lease, writes ours if it is free (or stolen), and reads it back after `settle`.
+    // This isn't mutual exclusion: without conditional writes, two servers whose writes land
+    // further apart than `settle` (i.e. on a slow or eventually consistent S3) may both take it.
+    fn take(name: String, storage: Arc<Box<dyn ObjectStorage>>, owner: String, steal: bool, settle: Duration) -> Result<Lease, Error> {
+        if storage.exists(String::from(LEASE_OBJECT))? {
+            let current = read_record(storage.as_ref().as_ref())?;
+            if current.owner != owner && !current.expired() {
+                if !steal {
+                    return Err(Error::new(
+                        ErrorKind::WouldBlock,
+                        format!(
+                            "Volume of `{}` is leased by {} on {} for another {}s, see --steal-lease",
+                            name, current.owner, current.host, current.expires.saturating_sub(now()),
+                        ),
+                    ))
+                }
+                log::warn!("Stealing the lease on `{}` from {} on {}", name, current.owner, current.host);
+            }
+        }
+
+        let record = LeaseRecord::new(owner.clone());
+        storage.write(String::from(LEASE_OBJECT), &record.encode())?;
+        storage.persist_object(String::from(LEASE_OBJECT))?;
+        // another server may have written its lease at the same time
+        thread::sleep(settle);
+        let current = read_record(storage.as_ref().as_ref())?;
+        if current.owner != owner {
+            return Err(Error::new(
+                ErrorKind::WouldBlock,
+                format!("Volume of `{}` was leased by {} on {} at the same time", name, current.owner, current.host),
+            ))
+        }
+        log::info!("Leased the volume of `{}` as {}", name, owner);
+
+        let state = LeaseState::new();
+        let (stop, stopped) = mpsc::channel();
+        let renewer = {
+            let name = name.clone();
+            let storage = Arc::clone(&storage);
+            let state = Arc::clone(&state);
+            thread::Builder::new()
+                .name(format!("lease-{}", name))
+                .spawn(move || renew(name, storage, record, state, stopped))?
+        };
+        Ok(Lease {
+            name,
+            state,
+            holder: Some(Holder::Object { storage, owner, stop, renewer }),
+        })
+    }
+
+    // false once the lease was taken over by another server, or expired while it couldn't be
+    // renewed
+    pub fn is_held(&self) -> bool {
+        self.state.held.load(Ordering::SeqCst)
+    }
+
+    // Fences `driver` once the lease is lost, or right away if it already is
+    pub fn fence_on_loss(&self, driver: &Arc<Driver>) {
+        let mut drivers = self.state.drivers.lock().unwrap();
+        if !self.is_held() {
+            driver.write().unwrap().fence();
+            return
+        }
+        drivers.retain(|driver| driver.strong_count() > 0);
+        drivers.push(Arc::downgrade(driver));
+    }
+}
+
+fn renew(name: String, storage: Arc<Box<dyn ObjectStorage>>, mut record: LeaseRecord, state: Arc<LeaseState>, stopped: mpsc::Receiver<()>) {
+    while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(RENEW_INTERVAL) {
+        match read_record(storage.as_ref().as_ref()) {
+            Ok(current) if current.owner != record.owner => {
+                log::error!("The lease on `{}` was taken by {} on {}, it is read-only from now on", name, current.owner, current.host);
+                state.lose();
+                return
+            },
+            Ok(_) => {
+                let renewed = record.renewed();
+                let written = storage.write(String::from(LEASE_OBJECT), &renewed.encode())
+                    .and_then(|_| storage.persist_object(String::from(LEASE_OBJECT)));
+                match written {
+                    Ok(_) => record = renewed,
+                    Err(e) => log::warn!("Couldn't renew the lease on `{}`: {}", name, e),
+                }
+            },
+            Err(e) => log::warn!("Couldn't renew the lease on `{}`: {}", name, e),
+        }
+        if record.expired() {
+            log::error!("The lease on `{}` expired, it is read-only from now on", name);
+            state.lose();
+            return
+        }
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        // lock files are left in place, removing them would race with the next server
+        if let Some(Holder::Object { storage, owner, stop, renewer }) = self.holder.take() {
+            drop(stop);
+            if renewer.join().is_err() {
+                log::warn!("The renewer of the lease on `{}` panicked", self.name);
+            }
+            match read_record(storage.as_ref().as_ref()) {
+                Ok(current) if current.owner == owner => {
+                    if let Err(e) = storage.delete(String::from(LEASE_OBJECT)) {
+                        log::warn!("Couldn't release the lease on `{}`: {}", self.name, e);
+                        return
+                    }
+                },
+                _ => return,
+            }
+        }
+        log::info!("Released the lease on `{}`", self.name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::block_storage_with_config;
+    use crate::object::FileBackend;
+    use crate::util::test_utils::TempFolder;
+
+    fn config(driver: &str, conn_str: String) -> BlockStorageConfig {
+        BlockStorageConfig {
+            export_name: Some(String::from("test")),
+            export_size: None,
+            export_force: false,
+            driver: String::from(driver),
+            conn_str,
+            init_volume: false,
+            read_only: false,
+        }
+    }
+
+    #[test]
+    fn test_lease_lock() {
+        let folder = TempFolder::new();
+        let config = config("sharded", format!("cache:file:///{}", folder.path));
+
+        let lease = Lease::acquire(&config, false).unwrap();
+        assert!(lease.is_held());
+        assert!(Path::new(&folder.path).join(LEASE_OBJECT).exists());
+        let err = Lease::acquire(&config, true).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        drop(lease);
+        assert!(Lease::acquire(&config, false).is_ok());
+    }
+
+    #[test]
+    fn test_lease_object() {
+        let folder = TempFolder::new();
+        let storage: Arc<Box<dyn ObjectStorage>> = Arc::new(Box::new(FileBackend::new(folder.path.clone(), false)));
+        let record = LeaseRecord::new(String::from("1-00000001"));
+        assert_eq!(LeaseRecord::decode(&record.encode()).unwrap(), record);
+
+        let first = Lease::take(String::from("test"), Arc::clone(&storage), String::from("1-00000001"), false, Duration::ZERO).unwrap();
+        let err = Lease::take(String::from("test"), Arc::clone(&storage), String::from("2-00000002"), false, Duration::ZERO).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+
+        let second = Lease::take(String::from("test"), Arc::clone(&storage), String::from("2-00000002"), true, Duration::ZERO).unwrap();
+        assert_eq!(read_record(storage.as_ref().as_ref()).unwrap().owner, "2-00000002");
+        // the first one doesn't remove the lease of the second
+        drop(first);
+        assert!(storage.exists(String::from(LEASE_OBJECT)).unwrap());
+        drop(second);
+        assert!(!storage.exists(String::from(LEASE_OBJECT)).unwrap());
+    }
+
+    #[test]
+    fn test_lease_fence_on_loss() {
+        let folder = TempFolder::new();
+        let mut config = config("sharded", format!("cache:file:///{}", folder.path));
+        config.export_size = Some(16 * 1024 * 1024);
+        config.init_volume = true;
+        let driver: Arc<Driver> = Arc::new(RwLock::new(block_storage_with_config(config.clone()).unwrap()));
+        let lease = Lease::acquire(&config, false).unwrap();
+        lease.fence_on_loss(&driver);
+
+        driver.write().unwrap().write(0, 4, &[1_u8; 4]).unwrap();
+        lease.state.lose();
+        assert!(!lease.is_held());
+        // the unwritten block is gone, and nothing is written from now on
+        assert!(driver.write().unwrap().write(0, 4, &[2_u8; 4]).is_err());
+        assert_eq!(driver.read().unwrap().read(0, 4).unwrap(), vec![0_u8; 4]);
+        driver.write().unwrap().close();
+        assert!(!Path::new(&folder.path).join("block-0").exists());
+    }
+}
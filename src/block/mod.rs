@@ -15,12 +15,26 @@ mod distributed;
 pub use self::distributed::DistributedBlock;
 pub use self::sharded::ShardedBlock;
 
-use crate::util::{Propagation, AlignedBlockIter};
+use crate::object::ObjectStorage;
+use crate::util::{Propagation, Extent, AlignedBlockIter};
 
 mod shard_distribution;
 pub use self::shard_distribution::ShardDistribution;
 
-pub trait BlockStorage {
+mod lease;
+pub use self::lease::Lease;
+
+// Shards that were never written are already deleted, i.e. when trimming unallocated space
+fn delete_shard(object_storage: &dyn ObjectStorage, object_name: String) -> Result<Propagation, Error> {
+    match object_storage.delete(object_name) {
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(Propagation::Guaranteed),
+        result => result,
+    }
+}
+
+// default implementations ignore their arguments
+#[allow(unused_variables)]
+pub trait BlockStorage: Send + Sync {
     fn init(&mut self, init_volume: bool) -> Result<(), Box<dyn std::error::Error>>;
     fn init_volume(&mut self) -> Result<(), Box<dyn std::error::Error>>;
     fn check_volume(&mut self) -> Result<(), Box<dyn std::error::Error>>;
@@ -30,11 +44,20 @@ pub trait BlockStorage {
     fn supports_trim(&self) -> bool {
         false
     }
+    // whether `block_status` is cheap enough to run before every structured read, i.e. it doesn't
+    // query the object storage for every shard
+    fn supports_fast_block_status(&self) -> bool {
+        false
+    }
     fn read(&self, offset: u64, length: usize) -> Result<Vec<u8>, Error>;
     fn write(&mut self, offset: u64, length: usize, data: &[u8]) -> Result<Propagation, Error>;
-    fn flush(&mut self, offset: u64, length: usize) -> Result<Propagation, Error>;
+    fn flush(&self, offset: u64, length: usize) -> Result<Propagation, Error>;
     fn close(&mut self);
 
+    // the lease on the volume was lost, see `ObjectStorage::fence`
+    fn fence(&mut self) {
+    }
+
     // `fill` has a default implementation
     fn fill(&mut self, offset: u64, length: usize, fillbyte: u8) -> Result<Propagation, Error> {
         // Don't allocate too big memory at once
@@ -51,6 +74,32 @@ pub trait BlockStorage {
         Ok(overall_propagation)
     }
 
+    // `resize` is only supported by drivers that can change their volume size in place
+    fn resize(&mut self, new_size: u64) -> Result<(), Error> {
+        Err(Error::new(ErrorKind::Unsupported, "Resize Not Supported"))
+    }
+
+    // by default, report the whole range as allocated data
+    fn block_status(&self, offset: u64, length: usize) -> Result<Vec<Extent>, Error> {
+        Ok(vec![Extent::data(length as u64)])
+    }
+
+    // `prefetch` is only a hint, so it is a noop by default
+    fn prefetch(&self, offset: u64, length: usize) -> Result<(), Error> {
+        Ok(())
+    }
+
+    // `write_zeroes` punches holes via `trim` when allowed, and falls back to `fill`
+    fn write_zeroes(&mut self, offset: u64, length: usize, no_hole: bool) -> Result<Propagation, Error> {
+        if !no_hole && self.supports_trim() {
+            match self.trim(offset, length) {
+                Err(err) if err.kind() == ErrorKind::Unsupported => {},
+                result => return result,
+            }
+        }
+        self.fill(offset, length, 0)
+    }
+
     // default sub-optimal implementation for `trim`
     fn trim(&mut self, offset: u64, length: usize) -> Result<Propagation, Error> {
         Err(Error::new(ErrorKind::Unsupported, "Not Supported"))
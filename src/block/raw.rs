@@ -7,11 +7,12 @@ use crate::{
     object::{ObjectStorage, object_storage_with_config},
     block::{BlockStorage, BlockStorageConfig},
 };
-use crate::util::Propagation;
+use crate::util::{Propagation, Extent};
 
 // Driver: RawBlock
 
 pub struct RawBlock {
+    #[allow(dead_code)]
     export_name: Option<String>,
     name: String,
     path: String,
@@ -26,7 +27,7 @@ impl RawBlock {
         let filename = segments.path_segments().unwrap().last().unwrap();
         let new_config = segments.as_str().strip_suffix(filename).unwrap();
 
-        let object_storage = object_storage_with_config(String::from(new_config)).unwrap();
+        let object_storage = object_storage_with_config(String::from(new_config), config.read_only).unwrap();
         if !object_storage.supports_random_write_access() {
             panic!("Object storage should support random write access for RawBlock.");
         }
@@ -72,7 +73,7 @@ impl BlockStorage for RawBlock {
         }
 
         let volume_size = self.config.export_size.unwrap() as u64;
-        self.object_storage.create_object(self.name.clone(), volume_size);
+        self.object_storage.create_object(self.name.clone(), volume_size)?;
         log::info!("Volume size is written.");
         
         self.volume_size = volume_size;
@@ -103,6 +104,11 @@ impl BlockStorage for RawBlock {
         self.object_storage.supports_trim()
     }
 
+    // a SEEK_DATA/SEEK_HOLE walk of the image
+    fn supports_fast_block_status(&self) -> bool {
+        true
+    }
+
     fn read(&self, offset: u64, length: usize) -> Result<Vec<u8>, Error> {
         self.object_storage
             .partial_read(self.name.clone(), offset, length)
@@ -113,11 +119,34 @@ impl BlockStorage for RawBlock {
             .partial_write(self.name.clone(), offset, length, data)
     }
 
-    fn flush(&mut self, offset: u64, length: usize) -> Result<Propagation, Error> {
+    fn flush(&self, _offset: u64, _length: usize) -> Result<Propagation, Error> {
         self.object_storage
             .persist_object(self.name.clone())
     }
 
+    fn resize(&mut self, new_size: u64) -> Result<(), Error> {
+        if new_size == 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "Volume size can not be zero"));
+        }
+        self.object_storage
+            .resize_object(self.name.clone(), new_size)?;
+        log::info!("Volume is resized from {} to {}", self.volume_size, new_size);
+        self.volume_size = new_size;
+        Ok(())
+    }
+
+    fn block_status(&self, offset: u64, length: usize) -> Result<Vec<Extent>, Error> {
+        match self.object_storage.object_extents(self.name.clone(), offset, length) {
+            Err(err) if err.kind() == ErrorKind::Unsupported => Ok(vec![Extent::data(length as u64)]),
+            result => result,
+        }
+    }
+
+    fn prefetch(&self, offset: u64, length: usize) -> Result<(), Error> {
+        self.object_storage
+            .prefetch_object(self.name.clone(), offset, length)
+    }
+
     fn trim(&mut self, offset: u64, length: usize) -> Result<Propagation, Error> {
         self.object_storage
             .trim_object(self.name.clone(), offset, length)
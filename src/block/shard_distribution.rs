@@ -23,6 +23,7 @@ impl ShardDistribution {
     }
 }
 
+#[allow(dead_code)]
 #[derive(PartialEq)]
 pub struct ReplicaIdentity {
     shard_idx: usize,
@@ -30,6 +31,7 @@ pub struct ReplicaIdentity {
 }
 
 impl ReplicaIdentity {
+    #[allow(dead_code)]
     fn new(shard_idx: usize, replica_idx: u8) -> ReplicaIdentity {
         ReplicaIdentity {
             shard_idx,
@@ -38,6 +40,7 @@ impl ReplicaIdentity {
     }
 }
 
+#[allow(dead_code)]
 pub struct DistributionSetup {
     n_nodes: u8,
     n_replicas: u8,
@@ -63,7 +66,7 @@ mod tests {
 
     fn simulate_distribution(setup: DistributionSetup) -> DistributionResult {
         let mut res = DistributionResult::new();
-        for node_idx in 0..setup.n_nodes {
+        for _node_idx in 0..setup.n_nodes {
             let replicas_in_node: Vec<ReplicaIdentity> = Vec::new();
             res.nodes.push(replicas_in_node);
         }
@@ -96,7 +99,7 @@ mod tests {
         assert_eq!(res.nodes.len() as u8, n_nodes);
 
         for shard_idx in 0..n_shards {
-            for replica_idx in 0..n_replicas {
+            for _replica_idx in 0..n_replicas {
                 let entry = ReplicaIdentity::new(shard_idx, 0);
                 assert!(res.nodes[0].contains(&entry));
             }
@@ -7,9 +7,9 @@ use log;
 
 use crate::{
     object::{ObjectStorage, object_storage_with_config},
-    block::{BlockStorage, BlockStorageConfig},
+    block::{BlockStorage, BlockStorageConfig, delete_shard},
 };
-use crate::util::Propagation;
+use crate::util::{Propagation, Extent, AlignedBlockIter, push_extent};
 
 // Driver: ShardedBlock
 
@@ -32,7 +32,7 @@ impl ShardedBlock {
             name: config.export_name.clone(),
             volume_size: 0_u64,
             shard_size: default_shard_size,
-            object_storage: object_storage_with_config(conn_str).unwrap(),
+            object_storage: object_storage_with_config(conn_str, config.read_only).unwrap(),
             config: config.clone(),
         };
 
@@ -95,8 +95,8 @@ impl BlockStorage for ShardedBlock {
         }
             
         let size_str = volume_size.to_string();
-        self.object_storage.write(String::from("size"), &size_str.as_bytes());
-        self.object_storage.persist_object(String::from("size"));
+        self.object_storage.write(String::from("size"), &size_str.as_bytes())?;
+        self.object_storage.persist_object(String::from("size"))?;
         log::info!("Initializing volume with size: {}", volume_size);
         log::info!("Volume size is written.");
         
@@ -170,7 +170,10 @@ impl BlockStorage for ShardedBlock {
                     continue;
                 }
                 if i == end {
-                    let read_size = ((length as u64 + offset % self.shard_size) % self.shard_size) as usize;
+                    let mut read_size = ((length as u64 + offset % self.shard_size) % self.shard_size) as usize;
+                    if read_size == 0 {
+                        read_size = self.shard_size as usize;
+                    }
                     buffer.extend_from_slice(&vec![0_u8; read_size]);
                     break;
                 }
@@ -249,7 +252,7 @@ impl BlockStorage for ShardedBlock {
         Ok(overall_propagation)
     }
 
-    fn flush(&mut self, offset: u64, length: usize) -> Result<Propagation, Error> {
+    fn flush(&self, offset: u64, length: usize) -> Result<Propagation, Error> {
         let start = self.shard_index(offset);
         let end = if 0 == (offset + length as u64) % self.shard_size {
             self.shard_index(offset + length as u64) - 1
@@ -275,6 +278,78 @@ impl BlockStorage for ShardedBlock {
         Ok(overall_propagation)
     }
 
+    fn resize(&mut self, new_size: u64) -> Result<(), Error> {
+        if new_size == 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "Volume size can not be zero"));
+        }
+
+        // the volume can't be opened without its size, so it is never deleted or partially written
+        let size_str = new_size.to_string();
+        self.object_storage.replace(String::from("size"), size_str.as_bytes())?;
+        self.object_storage.persist_object(String::from("size"))?;
+
+        if new_size < self.volume_size {
+            // zero the tail of the new last shard, so growing again exposes zeroes
+            let last_shard = self.shard_index(new_size - 1);
+            let tail_offset = new_size % self.shard_size;
+            let shard_name = self.shard_name(last_shard);
+            if tail_offset > 0 && self.object_storage.exists(shard_name.clone())? {
+                let tail_size = (self.shard_size - tail_offset) as usize;
+                self.object_storage.partial_write(shard_name, tail_offset, tail_size, &vec![0_u8; tail_size])?;
+            }
+            for i in (last_shard + 1)..=self.shard_index(self.volume_size - 1) {
+                let shard_name = self.shard_name(i);
+                if self.object_storage.exists(shard_name.clone())? {
+                    self.object_storage.delete(shard_name)?;
+                }
+            }
+        }
+
+        log::info!("Volume is resized from {} to {}", self.volume_size, new_size);
+        self.volume_size = new_size;
+        Ok(())
+    }
+
+    fn block_status(&self, offset: u64, length: usize) -> Result<Vec<Extent>, Error> {
+        let mut extents: Vec<Extent> = Vec::new();
+        for r in (AlignedBlockIter{ from: offset as usize, to: offset as usize + length, blksize: self.shard_size as usize }) {
+            let shard_name = self.shard_name(self.shard_index(r.start as u64));
+            let extent_len = (r.end - r.start) as u64;
+            if self.object_storage.exists(shard_name)? {
+                push_extent(&mut extents, Extent::data(extent_len));
+            } else {
+                push_extent(&mut extents, Extent::hole(extent_len));
+            }
+        }
+        Ok(extents)
+    }
+
+    fn prefetch(&self, offset: u64, length: usize) -> Result<(), Error> {
+        let start = self.shard_index(offset);
+        let end = if 0 == (offset + length as u64) % self.shard_size {
+            self.shard_index(offset + length as u64) - 1
+        } else {
+            self.shard_index(offset + length as u64)
+        };
+
+        log::debug!("storage::prefetch(start: {}, end: {})", start, end);
+        for i in start..=end {
+            let shard_name = self.shard_name(i);
+            // missing shards are holes, nothing to warm up
+            if !self.object_storage.exists(shard_name.clone())? {
+                continue;
+            }
+            let shard_start = std::cmp::max(offset, i as u64 * self.shard_size);
+            let shard_end = std::cmp::min(offset + length as u64, (i as u64 + 1) * self.shard_size);
+            self.object_storage.prefetch_object(
+                shard_name,
+                shard_start % self.shard_size,
+                (shard_end - shard_start) as usize
+            )?;
+        }
+        Ok(())
+    }
+
     fn trim(&mut self, offset: u64, length: usize) -> Result<Propagation, Error> {
         let start = self.shard_index(offset);
         let end = if 0 == (offset + length as u64) % self.shard_size {
@@ -289,7 +364,7 @@ impl BlockStorage for ShardedBlock {
             if i == start {
                 let trim_size = std::cmp::min((self.shard_size - offset % self.shard_size) as usize, length);
                 if trim_size as u64 % self.shard_size == 0 {
-                    overall_propagation = self.object_storage.delete(object_name)?;
+                    overall_propagation = delete_shard(self.object_storage.as_ref(), object_name)?;
                 } else {
                     overall_propagation = self.object_storage.partial_write(
                         object_name,
@@ -301,7 +376,7 @@ impl BlockStorage for ShardedBlock {
             } else if i == end {
                 let trim_size = ((length as u64 + offset % self.shard_size) % self.shard_size) as usize;
                 if trim_size as u64 % self.shard_size == 0 {
-                    overall_propagation = self.object_storage.delete(object_name)?;
+                    overall_propagation = delete_shard(self.object_storage.as_ref(), object_name)?;
                 } else {
                     overall_propagation = self.object_storage.partial_write(
                         object_name,
@@ -311,12 +386,16 @@ impl BlockStorage for ShardedBlock {
                     )?;
                 }
             } else {
-                overall_propagation = self.object_storage.delete(object_name)?;
+                overall_propagation = delete_shard(self.object_storage.as_ref(), object_name)?;
             }
         }
         Ok(overall_propagation)
     }
 
+    fn fence(&mut self) {
+        self.object_storage.fence();
+    }
+
     fn close(&mut self) {
         log::debug!("storage::close");
         self.object_storage.close();
@@ -339,7 +418,7 @@ mod sharded_tests {
                             .create(true)
                             .open(Path::new(&path).join("size"))
                             .unwrap();
-        size_file.write(format!("{}", size).as_bytes());
+        size_file.write(format!("{}", size).as_bytes()).unwrap();
         
         let config = BlockStorageConfig{
             export_name: Some("test".to_string()),
@@ -348,6 +427,7 @@ mod sharded_tests {
             driver: "sharded".to_string(),
             conn_str: format!("file:///{}", path),
             init_volume: false,
+            read_only: false,
         };
 
         let sharded_block = ShardedBlock::new(config);
@@ -363,8 +443,8 @@ mod sharded_tests {
         let folder = TempFolder::new();
         let mut sharded_block = init_sharded_block(16777216, folder.path.clone());
 
-        sharded_block.write(0_u64, 16 * 1024 * 1024 as usize, &[1_u8; 16 * 1024 * 1024]);
-        sharded_block.trim(0_u64, 12 * 1024 * 1024 as usize);
+        sharded_block.write(0_u64, 16 * 1024 * 1024 as usize, &[1_u8; 16 * 1024 * 1024]).unwrap();
+        sharded_block.trim(0_u64, 12 * 1024 * 1024 as usize).unwrap();
         assert!(Path::new(&format!("{}/block-0", folder.path.clone())).exists() == false);
         assert!(Path::new(&format!("{}/block-1", folder.path.clone())).exists() == false);
         assert!(Path::new(&format!("{}/block-2", folder.path.clone())).exists() == false);
@@ -377,9 +457,9 @@ mod sharded_tests {
         // deletion of the first shard but partially write zeroes to the last shard
         let folder = TempFolder::new();
         let mut sharded_block = init_sharded_block(16777216, folder.path.clone());
-        sharded_block.write(0_u64, 16 * 1024 * 1024 as usize, &[1_u8; 16 * 1024 * 1024]);
+        sharded_block.write(0_u64, 16 * 1024 * 1024 as usize, &[1_u8; 16 * 1024 * 1024]).unwrap();
 
-        sharded_block.trim(0_u64, 12 * 1024 * 1024 - 10 as usize);
+        sharded_block.trim(0_u64, 12 * 1024 * 1024 - 10 as usize).unwrap();
         assert!(Path::new(&format!("{}/block-0", folder.path.clone())).exists() == false);
         assert!(Path::new(&format!("{}/block-1", folder.path.clone())).exists() == false);
         assert!(Path::new(&format!("{}/block-2", folder.path.clone())).exists() == true);
@@ -396,9 +476,9 @@ mod sharded_tests {
         // in deletion of the last shard but partially write zeroes to the first shard
         let folder = TempFolder::new();
         let mut sharded_block = init_sharded_block(16777216, folder.path.clone());
-        sharded_block.write(0_u64, 16 * 1024 * 1024 as usize, &[1_u8; 16 * 1024 * 1024]);
+        sharded_block.write(0_u64, 16 * 1024 * 1024 as usize, &[1_u8; 16 * 1024 * 1024]).unwrap();
 
-        sharded_block.trim(10_u64, 12 * 1024 * 1024 - 10 as usize);
+        sharded_block.trim(10_u64, 12 * 1024 * 1024 - 10 as usize).unwrap();
         assert!(Path::new(&format!("{}/block-0", folder.path.clone())).exists() == true);
         assert!(Path::new(&format!("{}/block-1", folder.path.clone())).exists() == false);
         assert!(Path::new(&format!("{}/block-2", folder.path.clone())).exists() == false);
@@ -414,9 +494,9 @@ mod sharded_tests {
         // Trim range only contains a intermediary part of a single shard
         let folder = TempFolder::new();
         let mut sharded_block = init_sharded_block(16777216, folder.path.clone());
-        sharded_block.write(0_u64, 16 * 1024 * 1024 as usize, &[1_u8; 16 * 1024 * 1024]);
+        sharded_block.write(0_u64, 16 * 1024 * 1024 as usize, &[1_u8; 16 * 1024 * 1024]).unwrap();
 
-        sharded_block.trim(10_u64, 4 * 1024 * 1024 - 20 as usize);
+        sharded_block.trim(10_u64, 4 * 1024 * 1024 - 20 as usize).unwrap();
         assert!(Path::new(&format!("{}/block-0", folder.path.clone())).exists() == true);
         let read_result = sharded_block.read(0_u64, sharded_block.shard_size as usize).unwrap();
         let mut expected_read_result = vec![1_u8; 10];
@@ -431,9 +511,9 @@ mod sharded_tests {
         // Trim range overlaps from one shard to another, fully contains neither of them
         let folder = TempFolder::new();
         let mut sharded_block = init_sharded_block(16777216, folder.path.clone());
-        sharded_block.write(0_u64, 16 * 1024 * 1024 as usize, &[1_u8; 16 * 1024 * 1024]);
+        sharded_block.write(0_u64, 16 * 1024 * 1024 as usize, &[1_u8; 16 * 1024 * 1024]).unwrap();
 
-        sharded_block.trim(10_u64, 4 * 1024 * 1024 as usize);
+        sharded_block.trim(10_u64, 4 * 1024 * 1024 as usize).unwrap();
         assert!(Path::new(&format!("{}/block-0", folder.path.clone())).exists() == true);
         assert!(Path::new(&format!("{}/block-1", folder.path.clone())).exists() == true);
         let read_result = sharded_block.read(0_u64, 2 * sharded_block.shard_size as usize).unwrap();
@@ -442,4 +522,89 @@ mod sharded_tests {
         expected_read_result.extend_from_slice(&vec![1_u8; sharded_block.shard_size as usize - 10]);
         assert!(read_result == expected_read_result);
     }
+
+    #[test]
+    fn test_sharded_block_file_object_trim_case_6() {
+        // Case 6:
+        // Trim range contains shards that were never written, i.e. fstrim of unallocated space
+        let folder = TempFolder::new();
+        let mut sharded_block = init_sharded_block(16777216, folder.path.clone());
+        sharded_block.write(4 * 1024 * 1024 as u64, 10 as usize, &[1_u8; 10]).unwrap();
+
+        sharded_block.trim(0_u64, 16 * 1024 * 1024 as usize).unwrap();
+        assert!(Path::new(&format!("{}/block-1", folder.path.clone())).exists() == false);
+        let read_result = sharded_block.read(0_u64, 16 * 1024 * 1024 as usize).unwrap();
+        assert!(read_result == vec![0_u8; 16 * 1024 * 1024]);
+    }
+
+    #[test]
+    fn test_sharded_block_file_object_write_zeroes() {
+        // Zeroing without NO_HOLE punches holes, with NO_HOLE keeps the shards allocated.
+        // Either way, the zeroed range must read back in full.
+        let folder = TempFolder::new();
+        let mut sharded_block = init_sharded_block(16777216, folder.path.clone());
+        sharded_block.write(0_u64, 16 * 1024 * 1024 as usize, &[1_u8; 16 * 1024 * 1024]).unwrap();
+
+        sharded_block.write_zeroes(4 * 1024 * 1024 as u64, 4 * 1024 * 1024 as usize, true).unwrap();
+        assert!(Path::new(&format!("{}/block-1", folder.path.clone())).exists() == true);
+        sharded_block.write_zeroes(10_u64, 8 * 1024 * 1024 - 10 as usize, false).unwrap();
+        assert!(Path::new(&format!("{}/block-0", folder.path.clone())).exists() == true);
+        assert!(Path::new(&format!("{}/block-1", folder.path.clone())).exists() == false);
+        let read_result = sharded_block.read(0_u64, 2 * sharded_block.shard_size as usize).unwrap();
+        let mut expected_read_result = vec![1_u8; 10];
+        expected_read_result.extend_from_slice(&vec![0_u8; 2 * sharded_block.shard_size as usize - 10]);
+        assert!(read_result == expected_read_result);
+    }
+
+    #[test]
+    fn test_sharded_block_file_object_write_zeroes_unallocated() {
+        // Zeroing a volume that was never written, i.e. by mkfs, finds no shards to delete
+        let folder = TempFolder::new();
+        let mut sharded_block = init_sharded_block(16777216, folder.path.clone());
+
+        sharded_block.write_zeroes(0_u64, 8 * 1024 * 1024 as usize, false).unwrap();
+        sharded_block.write_zeroes(8 * 1024 * 1024 + 10 as u64, 8 * 1024 * 1024 - 10 as usize, false).unwrap();
+        assert!(Path::new(&format!("{}/block-3", folder.path.clone())).exists() == false);
+        let read_result = sharded_block.read(0_u64, 16 * 1024 * 1024 as usize).unwrap();
+        assert!(read_result == vec![0_u8; 16 * 1024 * 1024]);
+    }
+
+    #[test]
+    fn test_sharded_block_file_object_resize() {
+        // Shrinking drops the shards past the new end and zeroes the tail of the last one,
+        // growing again exposes zeroes.
+        let folder = TempFolder::new();
+        let mut sharded_block = init_sharded_block(16777216, folder.path.clone());
+        sharded_block.write(0_u64, 16 * 1024 * 1024 as usize, &[1_u8; 16 * 1024 * 1024]).unwrap();
+
+        sharded_block.resize(6 * 1024 * 1024 as u64).unwrap();
+        assert!(sharded_block.get_volume_size() == 6 * 1024 * 1024);
+        assert!(sharded_block.size_of_volume() == 6 * 1024 * 1024);
+        assert!(Path::new(&format!("{}/block-1", folder.path.clone())).exists() == true);
+        assert!(Path::new(&format!("{}/block-2", folder.path.clone())).exists() == false);
+        assert!(Path::new(&format!("{}/block-3", folder.path.clone())).exists() == false);
+
+        sharded_block.resize(12 * 1024 * 1024 as u64).unwrap();
+        assert!(sharded_block.size_of_volume() == 12 * 1024 * 1024);
+        let read_result = sharded_block.read(4 * 1024 * 1024 as u64, 8 * 1024 * 1024 as usize).unwrap();
+        let mut expected_read_result = vec![1_u8; 2 * 1024 * 1024];
+        expected_read_result.extend_from_slice(&vec![0_u8; 6 * 1024 * 1024]);
+        assert!(read_result == expected_read_result);
+    }
+
+    #[test]
+    fn test_sharded_block_file_object_block_status() {
+        // Missing shards are reported as holes, adjacent extents are merged
+        let folder = TempFolder::new();
+        let mut sharded_block = init_sharded_block(16777216, folder.path.clone());
+        sharded_block.write(4 * 1024 * 1024 as u64, 10 as usize, &[1_u8; 10]).unwrap();
+        sharded_block.write(8 * 1024 * 1024 as u64, 10 as usize, &[1_u8; 10]).unwrap();
+
+        let extents = sharded_block.block_status(10_u64, 16 * 1024 * 1024 - 10 as usize).unwrap();
+        assert!(extents == vec![
+            Extent::hole(4 * 1024 * 1024 - 10),
+            Extent::data(8 * 1024 * 1024),
+            Extent::hole(4 * 1024 * 1024),
+        ]);
+    }
 }
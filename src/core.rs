@@ -1,9 +1,10 @@
 use std::error::Error;
 
-use crate::nbd::{NBDExport, NBDServer};
-use crate::block::{BlockStorageConfig, block_storage_with_config};
-use crate::util::{human_size_to_usize};
+use crate::nbd::{NBDExport, NBDServer, TlsConfig, ListenAddr};
+use crate::block::{BlockStorageConfig, Lease, block_storage_with_config};
+use crate::util::{human_size_to_usize, wait_termination_signal};
 use std::sync::{Arc, RwLock};
+use std::{process, thread, time::Duration};
 
 pub fn init_export(size_str: &str, driver_str: &str, driver_cfg_str: &str, force: bool) -> Result<(), Box<dyn Error>> {
     let size = human_size_to_usize(size_str)?;
@@ -15,15 +16,66 @@ pub fn init_export(size_str: &str, driver_str: &str, driver_cfg_str: &str, force
         driver: driver_str.to_string(),
         conn_str: driver_cfg_str.to_string(),
         init_volume: true,
+        read_only: false,
     };
 
     block_storage_with_config(config)?;
     Ok(())
 }
 
-pub fn serve_exports(exports: Vec::<Arc<RwLock<NBDExport>>>) -> Result<(), Box<dyn Error>> {
-    let mut server = NBDServer::new("0.0.0.0".to_string(), 10809, exports);
+pub fn resize_export(size_str: &str, driver_str: &str, driver_cfg_str: &str) -> Result<(), Box<dyn Error>> {
+    let size = human_size_to_usize(size_str)?;
+
+    let config = BlockStorageConfig {
+        export_name: None,
+        export_size: None,
+        export_force: false,
+        driver: driver_str.to_string(),
+        conn_str: driver_cfg_str.to_string(),
+        init_volume: false,
+        read_only: false,
+    };
+
+    // offline only, a running server wouldn't see the new size; served exports are resized by
+    // their clients with NBD_CMD_RESIZE instead
+    let lease = Lease::acquire(&config, false)
+        .map_err(|e| format!("Can't resize a volume that is in use: {}", e))?;
+    let mut block_storage = block_storage_with_config(config)?;
+    block_storage.resize(size as u64)?;
+    block_storage.close();
+    drop(lease);
+    Ok(())
+}
+
+pub struct TlsOptions<'a> {
+    pub cert: &'a str,
+    pub key: &'a str,
+    pub ca: Option<&'a str>,
+    pub required: bool,
+}
+
+// Expects SIGINT and SIGTERM to be blocked (see `util::block_termination_signals`), the server
+// shuts down on the first one of them.
+pub fn serve_exports(exports: Vec::<Arc<RwLock<NBDExport>>>, listen: Vec<ListenAddr>, tls: Option<TlsOptions>, shutdown_timeout: Duration) -> Result<(), Box<dyn Error>> {
+    let tls_config = match tls {
+        Some(tls) => Some(TlsConfig::new(tls.cert, tls.key, tls.ca, tls.required)?),
+        None => None,
+    };
+    let mut server = NBDServer::new(listen, exports, tls_config)?;
     server.listen();
+
+    let signal = wait_termination_signal();
+    log::info!("Received signal {}, shutting down (deadline: {:?})", signal, shutdown_timeout);
+    // a second signal doesn't wait for the sessions and exports
+    thread::spawn(|| {
+        wait_termination_signal();
+        log::error!("Received another signal, exiting without persisting the exports");
+        process::exit(1);
+    });
+    if !server.shutdown(shutdown_timeout) {
+        return Err("Shutdown didn't complete, exports may not have been persisted".into());
+    }
+    log::info!("Shutdown complete");
     Ok(())
 }
 
@@ -35,6 +87,7 @@ pub fn destroy_export(driver_str: &str, driver_cfg_str: &str) -> Result<(), Box<
         driver: driver_str.to_string(),
         conn_str: driver_cfg_str.to_string(),
         init_volume: false,
+        read_only: false,
     };
 
     let mut block_storage = block_storage_with_config(config)?;
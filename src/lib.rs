@@ -0,0 +1,6 @@
+
+pub mod object;
+pub mod block;
+pub mod util;
+pub mod nbd;
+pub mod core;
@@ -1,17 +1,10 @@
-#![allow(unused_variables)]
-#![allow(unused_must_use)]
-#![allow(dead_code)]
 
-use crate::nbd::NBDExport;
+use nbd_rs::nbd::{NBDExport, ExportOptions, ListenAddr};
+use nbd_rs::{core::*, util};
 use clap::{Arg, arg, command, Command};
 use std::sync::{Arc, RwLock};
-
-mod object;
-mod block;
-mod util;
-mod nbd;
-mod core;
-use crate::core::*;
+use std::process;
+use std::time::Duration;
 
 fn main() {
     env_logger::init();
@@ -32,9 +25,28 @@ fn main() {
                 Arg::new("e")
                 .long("export")
                 .value_names(&["EXPORT", "DRIVER", "DRIVER_CFG"])
+                .help("Export name, optionally followed by comma separated options (i.e. `mydisk,readonly`)")
                 .multiple_occurrences(true)
                 .required(true)
             )
+            .arg(
+                arg!(--listen <ADDR> "Address to accept connections on, `tcp://HOST:PORT` or `unix:PATH` (default: tcp://0.0.0.0:10809)")
+                .multiple_occurrences(true)
+                .required(false)
+            )
+            .arg(arg!(--"tls-cert" <FILE> "TLS certificate chain (PEM) for NBD_OPT_STARTTLS").required(false).requires("tls-key"))
+            .arg(arg!(--"tls-key" <FILE> "TLS private key (PEM)").required(false).requires("tls-cert"))
+            .arg(arg!(--"tls-ca" <FILE> "CA certificate (PEM) that client certificates must be signed with").required(false).requires("tls-cert"))
+            .arg(arg!(--"tls-required" "Refuse to negotiate anything but TLS").required(false).requires("tls-cert"))
+            .arg(arg!(--"shutdown-timeout" <SECONDS> "Time for sessions to finish their requests and exports to persist, on SIGINT/SIGTERM").required(false).default_value("30"))
+            .arg(arg!(--"steal-lease" "Take over the leases of exports served by another server, that one turns them read-only. Leases on object storages are best-effort, they don't guarantee a single writer").required(false))
+        )
+        .subcommand(
+            Command::new("resize")
+            .about("Resizes an export that is not being served.")
+            .arg(arg!(-s --size <SIZE> "New size of the export").required(true))
+            .arg(arg!([DRIVER] "Driver of the export").required(true))
+            .arg(arg!([DRIVER_CFG] "Driver config of the export").required(true))
         )
         .subcommand(
             Command::new("destroy")
@@ -53,22 +65,60 @@ fn main() {
         ),
 
         Some(("serve", sub_matches)) => {
+            // before the exports start their threads, see `serve_exports`
+            util::block_termination_signals();
             let export_strs: Vec<&str> = sub_matches.values_of("e").unwrap().collect();
             assert_eq!(export_strs.len() % 3, 0);
 
             let mut exports = Vec::<Arc<RwLock<NBDExport>>>::new();
+            let steal_lease = sub_matches.is_present("steal-lease");
 
             for i in 0..export_strs.len()/3 {
-                let export = Arc::new(RwLock::new(NBDExport::new(
-                            export_strs[i*3 + 0].to_string(),
+                let (name, options) = ExportOptions::from_spec(export_strs[i*3 + 0]).unwrap();
+                let export = NBDExport::new(
+                            name.clone(),
                             String::from(export_strs[i*3 +1]),
                             String::from(export_strs[i*3 +2]),
-                            )));
-                exports.push(export);
+                            options,
+                            steal_lease,
+                            );
+                match export {
+                    Ok(export) => exports.push(Arc::new(RwLock::new(export))),
+                    Err(e) => {
+                        log::error!("Couldn't open export {}: {}", name, e);
+                        // releases the leases taken so far
+                        drop(exports);
+                        process::exit(1);
+                    }
+                }
+            }
+            let listen: Vec<ListenAddr> = match sub_matches.values_of("listen") {
+                Some(specs) => specs.map(|spec| ListenAddr::from_spec(spec).unwrap()).collect(),
+                None => vec![ListenAddr::default()],
+            };
+            let tls = match sub_matches.value_of("tls-cert") {
+                Some(cert) => Some(TlsOptions {
+                    cert,
+                    key: sub_matches.value_of("tls-key").unwrap(),
+                    ca: sub_matches.value_of("tls-ca"),
+                    required: sub_matches.is_present("tls-required"),
+                }),
+                None => None,
+            };
+            let shutdown_timeout = Duration::from_secs(sub_matches.value_of_t("shutdown-timeout").unwrap());
+            if let Err(e) = serve_exports(exports, listen, tls, shutdown_timeout) {
+                log::error!("{}", e);
+                process::exit(1);
             }
-            serve_exports(exports)
+            Ok(())
         },
 
+        Some(("resize", sub_matches)) => resize_export(
+            sub_matches.value_of("size").unwrap(),
+            sub_matches.value_of("DRIVER").unwrap(),
+            sub_matches.value_of("DRIVER_CFG").unwrap(),
+            ),
+
         Some(("destroy", sub_matches)) => destroy_export(
             sub_matches.value_of("DRIVER").unwrap(),
             sub_matches.value_of("DRIVER_CFG").unwrap(),
@@ -0,0 +1,179 @@
+// Per-export access control. Clients are matched by their address on TCP connections, and by
+// the uid of the peer process on Unix domain sockets.
+
+use std::{
+    fmt,
+    io,
+    mem,
+    net::IpAddr,
+    os::unix::io::AsRawFd,
+};
+
+use crate::nbd::NBDSocket;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Peer {
+    Ip(IpAddr),
+    // uid of the peer process on Unix domain sockets
+    Uid(u32),
+    // only matches when there are no allow rules
+    Unknown,
+}
+
+impl Peer {
+    pub fn of(socket: &NBDSocket) -> Peer {
+        match socket {
+            NBDSocket::Tcp(socket) => match socket.peer_addr() {
+                Ok(addr) => Peer::Ip(addr.ip()),
+                Err(e) => {
+                    log::warn!("Couldn't get the peer address: {}", e);
+                    Peer::Unknown
+                }
+            },
+            NBDSocket::Unix(socket) => match peer_uid(socket.as_raw_fd()) {
+                Ok(uid) => Peer::Uid(uid),
+                Err(e) => {
+                    log::warn!("Couldn't get the peer credentials: {}", e);
+                    Peer::Unknown
+                }
+            },
+        }
+    }
+}
+
+fn peer_uid(fd: libc::c_int) -> Result<u32, io::Error> {
+    let mut cred: libc::ucred = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error())
+    }
+    Ok(cred.uid)
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AclRule {
+    // network address and prefix length, i.e. `10.0.0.0/8`
+    Cidr(IpAddr, u8),
+    // peer uid on Unix domain sockets, i.e. `uid:1000`
+    Uid(u32),
+}
+
+impl AclRule {
+    pub fn from_spec(spec: &str) -> Result<AclRule, String> {
+        if let Some(uid) = spec.strip_prefix("uid:") {
+            return uid.parse::<u32>()
+                .map(AclRule::Uid)
+                .map_err(|_| format!("Invalid uid `{}`", uid))
+        }
+        let (addr, prefix) = match spec.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (spec, None),
+        };
+        let addr = addr.parse::<IpAddr>().map_err(|_| format!("Invalid address `{}`", addr))?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => match prefix.parse::<u8>() {
+                Ok(prefix) if prefix <= max_prefix => prefix,
+                _ => return Err(format!("Invalid prefix length `{}`", prefix)),
+            },
+            None => max_prefix,
+        };
+        Ok(AclRule::Cidr(addr, prefix))
+    }
+
+    pub fn matches(&self, peer: &Peer) -> bool {
+        match (self, peer) {
+            (AclRule::Cidr(network, prefix), Peer::Ip(addr)) => {
+                // clients of dual-stack listeners show up as `::ffff:a.b.c.d`
+                let addr = match addr {
+                    IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(*addr),
+                    _ => *addr,
+                };
+                match (network, addr) {
+                    (IpAddr::V4(network), IpAddr::V4(addr)) =>
+                        prefix_matches(u32::from(*network) as u128, u32::from(addr) as u128, *prefix, 32),
+                    (IpAddr::V6(network), IpAddr::V6(addr)) =>
+                        prefix_matches(u128::from(*network), u128::from(addr), *prefix, 128),
+                    _ => false,
+                }
+            },
+            (AclRule::Uid(uid), Peer::Uid(peer_uid)) => uid == peer_uid,
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(network: u128, addr: u128, prefix: u8, bits: u8) -> bool {
+    if prefix == 0 {
+        return true
+    }
+    let shift = (bits - prefix) as u32;
+    (network >> shift) == (addr >> shift)
+}
+
+impl fmt::Display for AclRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AclRule::Cidr(addr, prefix) => f.write_str(&format!("{}/{}", addr, prefix)),
+            AclRule::Uid(uid) => f.write_str(&format!("uid:{}", uid)),
+        }
+    }
+}
+
+// Denied peers are refused, otherwise peers are allowed if they match an allow rule, or if
+// there are none.
+#[derive(Clone, Default, Debug, PartialEq)]
+pub struct Acl {
+    pub allow: Vec<AclRule>,
+    pub deny: Vec<AclRule>,
+}
+
+impl Acl {
+    pub fn permits(&self, peer: &Peer) -> bool {
+        if self.deny.iter().any(|rule| rule.matches(peer)) {
+            return false
+        }
+        self.allow.is_empty() || self.allow.iter().any(|rule| rule.matches(peer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acl_permits() {
+        let acl = Acl {
+            allow: vec![
+                AclRule::from_spec("10.0.0.0/8").unwrap(),
+                AclRule::from_spec("fd00::/8").unwrap(),
+                AclRule::from_spec("uid:1000").unwrap(),
+            ],
+            deny: vec![AclRule::from_spec("10.0.0.5").unwrap()],
+        };
+        let ip = |addr: &str| Peer::Ip(addr.parse().unwrap());
+        assert!(acl.permits(&ip("10.1.2.3")));
+        assert!(acl.permits(&ip("::ffff:10.1.2.3")));
+        assert!(acl.permits(&ip("fd12::1")));
+        assert!(!acl.permits(&ip("10.0.0.5")));
+        assert!(!acl.permits(&ip("192.168.1.1")));
+        assert!(acl.permits(&Peer::Uid(1000)));
+        assert!(!acl.permits(&Peer::Uid(0)));
+        assert!(!acl.permits(&Peer::Unknown));
+        assert!(Acl::default().permits(&Peer::Unknown));
+
+        assert_eq!(AclRule::from_spec("0.0.0.0/0"), Ok(AclRule::Cidr("0.0.0.0".parse().unwrap(), 0)));
+        assert!(AclRule::from_spec("10.0.0.0/33").is_err());
+        assert!(AclRule::from_spec("uid:root").is_err());
+        assert!(AclRule::from_spec("localhost").is_err());
+    }
+}
@@ -0,0 +1,418 @@
+// Encoding and decoding of everything exchanged with clients: the handshake, options and their
+// replies, and transmission requests and replies. Everything is read from a generic `Read` and
+// written to a generic `Write`, and malformed input is returned as an error instead of panicking,
+// so the session can refuse the option or drop the connection.
+
+use std::{
+    convert::TryInto,
+    io::{self, Read, Write, Error, ErrorKind},
+};
+
+use crate::nbd::proto;
+
+pub const NBD_MAGIC: &[u8; 8] = b"NBDMAGIC";
+pub const NBD_IHAVEOPT: u64 = 0x49484156454F5054; // "IHAVEOPT"
+pub const NBD_OPTION_REPLY_MAGIC: u64 = 0x3e889045565a9;
+
+// Option data is at most an export name and a few context queries, larger options are skipped
+// and refused with NBD_REP_ERR_TOO_BIG
+pub const MAX_OPTION_LENGTH: u32 = 64 * 1024;
+// The spec limits export names and metadata context names to 4096 bytes
+pub const MAX_STRING_LENGTH: u32 = 4096;
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32, Error> {
+    let mut buf = [0_u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64, Error> {
+    let mut buf = [0_u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+// Discards `length` bytes, to stay in sync with the client
+pub fn skip<R: Read>(reader: &mut R, length: u64) -> Result<(), Error> {
+    let skipped = io::copy(&mut reader.take(length), &mut io::sink())?;
+    if skipped < length {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed in the middle of data"))
+    }
+    Ok(())
+}
+
+// Handshake
+
+pub fn write_handshake<W: Write>(writer: &mut W, handshake_flags: u16) -> Result<(), Error> {
+    let mut buf = Vec::with_capacity(18);
+    buf.extend_from_slice(NBD_MAGIC);
+    buf.extend_from_slice(&NBD_IHAVEOPT.to_be_bytes());
+    buf.extend_from_slice(&handshake_flags.to_be_bytes());
+    writer.write_all(&buf)?;
+    writer.flush()
+}
+
+pub fn read_client_flags<R: Read>(reader: &mut R) -> Result<u32, Error> {
+    read_u32(reader)
+}
+
+// Options
+
+#[derive(Debug, PartialEq)]
+pub struct NBDOption {
+    pub option: u32,
+    // length the client sent, `data` is left empty when it is above MAX_OPTION_LENGTH
+    pub length: u32,
+    pub data: Vec<u8>,
+}
+
+impl NBDOption {
+    pub fn read<R: Read>(reader: &mut R) -> Result<NBDOption, Error> {
+        let magic = read_u64(reader)?;
+        if magic != NBD_IHAVEOPT {
+            let request_magic = (magic >> 32) as u32;
+            if request_magic == proto::NBD_REQUEST_MAGIC || request_magic == proto::NBD_EXTENDED_REQUEST_MAGIC {
+                return Err(invalid(String::from("Transmission request before NBD_OPT_GO")))
+            }
+            return Err(invalid(format!("Error at NBD_IHAVEOPT. Expected: {:#X} Got: {:#X}", NBD_IHAVEOPT, magic)))
+        }
+        let option = read_u32(reader)?;
+        let length = read_u32(reader)?;
+        if length > MAX_OPTION_LENGTH {
+            skip(reader, length as u64)?;
+            return Ok(NBDOption { option, length, data: Vec::new() })
+        }
+        let mut data = vec![0_u8; length as usize];
+        reader.read_exact(&mut data)?;
+        Ok(NBDOption { option, length, data })
+    }
+
+    pub fn too_big(&self) -> bool {
+        self.length > MAX_OPTION_LENGTH
+    }
+}
+
+// Reads the fields of option data, failing instead of reading past its end
+pub struct OptionData<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> OptionData<'a> {
+    pub fn new(data: &'a [u8]) -> OptionData<'a> {
+        OptionData { data }
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], Error> {
+        if length > self.data.len() {
+            return Err(invalid(format!("Option data is too short, {} more bytes expected", length - self.data.len())))
+        }
+        let (taken, rest) = self.data.split_at(length);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    // A UTF-8 string of `length` bytes
+    pub fn string(&mut self, length: u32) -> Result<String, Error> {
+        if length > MAX_STRING_LENGTH {
+            return Err(invalid(format!("String of {} bytes is too long", length)))
+        }
+        let bytes = self.take(length as usize)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| invalid(String::from("String is not valid UTF-8")))
+    }
+
+    // Trailing bytes mean the client and the server disagree on the layout
+    pub fn finish(self) -> Result<(), Error> {
+        if !self.data.is_empty() {
+            return Err(invalid(format!("{} unexpected bytes at the end of option data", self.data.len())))
+        }
+        Ok(())
+    }
+}
+
+// Data of NBD_OPT_INFO and NBD_OPT_GO
+#[derive(Debug, PartialEq)]
+pub struct InfoRequest {
+    pub name: String,
+    pub info_reqs: Vec<u16>,
+}
+
+impl InfoRequest {
+    pub fn decode(data: &[u8]) -> Result<InfoRequest, Error> {
+        let mut data = OptionData::new(data);
+        let name_length = data.u32()?;
+        let name = data.string(name_length)?;
+        let count = data.u16()?;
+        let mut info_reqs = Vec::new();
+        for _ in 0..count {
+            info_reqs.push(data.u16()?);
+        }
+        data.finish()?;
+        Ok(InfoRequest { name, info_reqs })
+    }
+}
+
+// Data of NBD_OPT_LIST_META_CONTEXT and NBD_OPT_SET_META_CONTEXT
+#[derive(Debug, PartialEq)]
+pub struct MetaContextRequest {
+    pub name: String,
+    pub queries: Vec<String>,
+}
+
+impl MetaContextRequest {
+    pub fn decode(data: &[u8]) -> Result<MetaContextRequest, Error> {
+        let mut data = OptionData::new(data);
+        let name_length = data.u32()?;
+        let name = data.string(name_length)?;
+        let count = data.u32()?;
+        let mut queries = Vec::new();
+        for _ in 0..count {
+            let query_length = data.u32()?;
+            queries.push(data.string(query_length)?);
+        }
+        data.finish()?;
+        Ok(MetaContextRequest { name, queries })
+    }
+}
+
+pub fn write_option_reply<W: Write>(writer: &mut W, option: u32, reply_type: u32, data: &[u8]) -> Result<(), Error> {
+    let mut buf = Vec::with_capacity(20 + data.len());
+    buf.extend_from_slice(&NBD_OPTION_REPLY_MAGIC.to_be_bytes());
+    buf.extend_from_slice(&option.to_be_bytes());
+    buf.extend_from_slice(&reply_type.to_be_bytes());
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.extend_from_slice(data);
+    writer.write_all(&buf)?;
+    writer.flush()
+}
+
+// NBD_REP_SERVER
+pub fn encode_server(name: &str, description: &str) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&(name.len() as u32).to_be_bytes());
+    buf.extend_from_slice(name.as_bytes());
+    buf.extend_from_slice(description.as_bytes());
+    buf
+}
+
+// NBD_REP_INFO with NBD_INFO_EXPORT
+pub fn encode_info_export(size: u64, transmission_flags: u16) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&proto::NBD_INFO_EXPORT.to_be_bytes());
+    buf.extend_from_slice(&size.to_be_bytes());
+    buf.extend_from_slice(&transmission_flags.to_be_bytes());
+    buf
+}
+
+// NBD_REP_INFO with NBD_INFO_NAME or NBD_INFO_DESCRIPTION
+pub fn encode_info_string(info_type: u16, value: &str) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&info_type.to_be_bytes());
+    buf.extend_from_slice(value.as_bytes());
+    buf
+}
+
+// NBD_REP_INFO with NBD_INFO_BLOCK_SIZE
+pub fn encode_info_block_size(minimum: u32, preferred: u32, maximum: u32) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&proto::NBD_INFO_BLOCK_SIZE.to_be_bytes());
+    buf.extend_from_slice(&minimum.to_be_bytes());
+    buf.extend_from_slice(&preferred.to_be_bytes());
+    buf.extend_from_slice(&maximum.to_be_bytes());
+    buf
+}
+
+// NBD_REP_META_CONTEXT
+pub fn encode_meta_context(id: u32, name: &str) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&id.to_be_bytes());
+    buf.extend_from_slice(name.as_bytes());
+    buf
+}
+
+// Reply to NBD_OPT_EXPORT_NAME, there is no option reply header
+pub fn write_export_name_reply<W: Write>(writer: &mut W, size: u64, transmission_flags: u16, no_zeroes: bool) -> Result<(), Error> {
+    let mut buf = Vec::with_capacity(134);
+    buf.extend_from_slice(&size.to_be_bytes());
+    buf.extend_from_slice(&transmission_flags.to_be_bytes());
+    if !no_zeroes {
+        buf.extend_from_slice(&[0_u8; 124]);
+    }
+    writer.write_all(&buf)?;
+    writer.flush()
+}
+
+// Transmission
+
+#[derive(Debug, PartialEq)]
+pub struct NBDRequest {
+    pub flags: u16,
+    pub req_type: u16,
+    pub handle: u64,
+    pub offset: u64,
+    pub length: u64,
+    // payload of NBD_CMD_WRITE
+    pub data: Vec<u8>,
+}
+
+impl NBDRequest {
+    // Reads the next request header. The payload of writes is left on the wire, to be read with
+    // `read_payload` or skipped once the request is validated.
+    pub fn read<R: Read>(reader: &mut R, extended_headers: bool) -> Result<NBDRequest, Error> {
+        let magic = read_u32(reader)?;
+        let expected_magic = match extended_headers {
+            true => proto::NBD_EXTENDED_REQUEST_MAGIC,
+            false => proto::NBD_REQUEST_MAGIC,
+        };
+        if magic != expected_magic {
+            return Err(invalid(format!("Error at NBD_REQUEST_MAGIC. Expected: {:#X} Got: {:#X}", expected_magic, magic)))
+        }
+
+        // extended headers carry 64-bit lengths
+        let mut header = vec![0_u8; if extended_headers { 28 } else { 24 }];
+        reader.read_exact(&mut header)?;
+        let length = match extended_headers {
+            true => u64::from_be_bytes(header[20..28].try_into().unwrap()),
+            false => u32::from_be_bytes(header[20..24].try_into().unwrap()) as u64,
+        };
+        let request = NBDRequest {
+            flags: u16::from_be_bytes(header[0..2].try_into().unwrap()),
+            req_type: u16::from_be_bytes(header[2..4].try_into().unwrap()),
+            handle: u64::from_be_bytes(header[4..12].try_into().unwrap()),
+            offset: u64::from_be_bytes(header[12..20].try_into().unwrap()),
+            length,
+            data: Vec::new(),
+        };
+        Ok(request)
+    }
+
+    pub fn has_payload(&self) -> bool {
+        self.req_type == proto::NBD_CMD_WRITE
+    }
+
+    pub fn read_payload<R: Read>(&mut self, reader: &mut R) -> Result<(), Error> {
+        self.data = vec![0; self.length as usize];
+        reader.read_exact(&mut self.data)
+    }
+
+    // Discards the payload of a refused write, so the next request can still be read
+    pub fn skip_payload<R: Read>(&self, reader: &mut R) -> Result<(), Error> {
+        skip(reader, self.length)
+    }
+}
+
+pub fn encode_simple_reply(buf: &mut Vec<u8>, error: u32, handle: u64) {
+    buf.extend_from_slice(&proto::NBD_SIMPLE_REPLY_MAGIC.to_be_bytes());
+    buf.extend_from_slice(&error.to_be_bytes());
+    buf.extend_from_slice(&handle.to_be_bytes());
+}
+
+// Header of a structured reply chunk, or of an extended reply when extended headers are in use
+pub fn encode_structured_reply(buf: &mut Vec<u8>, extended_headers: bool, flags: u16, reply_type: u16, handle: u64, offset: u64, length_of_payload: u32) {
+    if extended_headers {
+        buf.extend_from_slice(&proto::NBD_EXTENDED_REPLY_MAGIC.to_be_bytes());
+        buf.extend_from_slice(&flags.to_be_bytes());
+        buf.extend_from_slice(&reply_type.to_be_bytes());
+        buf.extend_from_slice(&handle.to_be_bytes());
+        buf.extend_from_slice(&offset.to_be_bytes());
+        buf.extend_from_slice(&(length_of_payload as u64).to_be_bytes());
+    } else {
+        buf.extend_from_slice(&proto::NBD_STRUCTURED_REPLY_MAGIC.to_be_bytes());
+        buf.extend_from_slice(&flags.to_be_bytes());
+        buf.extend_from_slice(&reply_type.to_be_bytes());
+        buf.extend_from_slice(&handle.to_be_bytes());
+        buf.extend_from_slice(&length_of_payload.to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn option_bytes(option: u32, data: &[u8]) -> Vec<u8> {
+        let mut buf = NBD_IHAVEOPT.to_be_bytes().to_vec();
+        buf.extend_from_slice(&option.to_be_bytes());
+        buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    #[test]
+    fn test_option_decoding() {
+        let mut go = Vec::new();
+        go.extend_from_slice(&6_u32.to_be_bytes());
+        go.extend_from_slice(b"MyDisk");
+        go.extend_from_slice(&1_u16.to_be_bytes());
+        go.extend_from_slice(&proto::NBD_INFO_BLOCK_SIZE.to_be_bytes());
+        let bytes = option_bytes(proto::NBD_OPT_GO, &go);
+
+        let option = NBDOption::read(&mut &bytes[..]).unwrap();
+        assert_eq!(option.option, proto::NBD_OPT_GO);
+        // names are no longer uppercased on the way in
+        assert_eq!(InfoRequest::decode(&option.data).unwrap(), InfoRequest {
+            name: String::from("MyDisk"),
+            info_reqs: vec![proto::NBD_INFO_BLOCK_SIZE],
+        });
+
+        // a name longer than the option data, and trailing bytes
+        assert!(InfoRequest::decode(&[0, 0, 0, 9, b'a', 0, 0]).is_err());
+        assert!(InfoRequest::decode(&[0, 0, 0, 1, b'a', 0, 0, 0]).is_err());
+        // truncated and mismatched headers
+        assert_eq!(NBDOption::read(&mut &bytes[..10]).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(NBDOption::read(&mut &b"IHAVEOPX\0\0\0\x01\0\0\0\0"[..]).unwrap_err().kind(), ErrorKind::InvalidData);
+        let mut request = proto::NBD_REQUEST_MAGIC.to_be_bytes().to_vec();
+        request.extend_from_slice(&[0_u8; 24]);
+        assert_eq!(NBDOption::read(&mut &request[..]).unwrap_err().to_string(), "Transmission request before NBD_OPT_GO");
+
+        // oversized options are skipped, so the next one can still be read
+        let mut bytes = option_bytes(proto::NBD_OPT_LIST, &vec![0_u8; MAX_OPTION_LENGTH as usize + 1]);
+        bytes.extend_from_slice(&option_bytes(proto::NBD_OPT_ABORT, &[]));
+        let mut reader = &bytes[..];
+        assert!(NBDOption::read(&mut reader).unwrap().too_big());
+        assert_eq!(NBDOption::read(&mut reader).unwrap().option, proto::NBD_OPT_ABORT);
+    }
+
+    #[test]
+    fn test_request_decoding() {
+        let mut bytes = proto::NBD_REQUEST_MAGIC.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&proto::NBD_CMD_FLAG_FUA.to_be_bytes());
+        bytes.extend_from_slice(&proto::NBD_CMD_WRITE.to_be_bytes());
+        bytes.extend_from_slice(&7_u64.to_be_bytes());
+        bytes.extend_from_slice(&4096_u64.to_be_bytes());
+        bytes.extend_from_slice(&3_u32.to_be_bytes());
+        bytes.extend_from_slice(b"abc");
+        let mut reader = &bytes[..];
+        let mut request = NBDRequest::read(&mut reader, false).unwrap();
+        assert!(request.has_payload());
+        request.read_payload(&mut reader).unwrap();
+        assert_eq!(request, NBDRequest {
+            flags: proto::NBD_CMD_FLAG_FUA,
+            req_type: proto::NBD_CMD_WRITE,
+            handle: 7,
+            offset: 4096,
+            length: 3,
+            data: b"abc".to_vec(),
+        });
+        // refused payloads are skipped
+        let mut reader = &bytes[..];
+        NBDRequest::read(&mut reader, false).unwrap().skip_payload(&mut reader).unwrap();
+        assert!(reader.is_empty());
+        // missing payload, and compact requests once extended headers were negotiated
+        let mut reader = &bytes[..30];
+        let mut request = NBDRequest::read(&mut reader, false).unwrap();
+        assert_eq!(request.read_payload(&mut reader).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(NBDRequest::read(&mut &bytes[..20], false).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(NBDRequest::read(&mut &bytes[..], true).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
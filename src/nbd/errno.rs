@@ -0,0 +1,40 @@
+// Maps storage errors to the errno values that are valid in transmission replies.
+// Clients only understand the NBD_E* values, so anything unknown is reported as NBD_EIO.
+
+use std::io::{Error, ErrorKind};
+
+use crate::nbd::proto;
+
+pub fn from_error(err: &Error) -> u8 {
+    if let Some(code) = err.raw_os_error() {
+        match code {
+            libc::EPERM | libc::EACCES | libc::EROFS => return proto::NBD_EPERM,
+            libc::ENOSPC | libc::EDQUOT | libc::EFBIG => return proto::NBD_ENOSPC,
+            libc::EINVAL => return proto::NBD_EINVAL,
+            libc::EOVERFLOW => return proto::NBD_EOVERFLOW,
+            libc::ESHUTDOWN => return proto::NBD_ESHUTDOWN,
+            _ => (),
+        }
+    }
+    match err.kind() {
+        ErrorKind::PermissionDenied => proto::NBD_EPERM,
+        ErrorKind::InvalidInput | ErrorKind::InvalidData | ErrorKind::Unsupported => proto::NBD_EINVAL,
+        _ => proto::NBD_EIO,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_error() {
+        assert_eq!(from_error(&Error::from_raw_os_error(libc::ENOSPC)), proto::NBD_ENOSPC);
+        assert_eq!(from_error(&Error::from_raw_os_error(libc::EROFS)), proto::NBD_EPERM);
+        assert_eq!(from_error(&Error::from_raw_os_error(libc::ESHUTDOWN)), proto::NBD_ESHUTDOWN);
+        assert_eq!(from_error(&Error::from_raw_os_error(libc::EIO)), proto::NBD_EIO);
+        assert_eq!(from_error(&Error::new(ErrorKind::PermissionDenied, "read-only")), proto::NBD_EPERM);
+        assert_eq!(from_error(&Error::new(ErrorKind::Unsupported, "Resize Not Supported")), proto::NBD_EINVAL);
+        assert_eq!(from_error(&Error::new(ErrorKind::Other, "shard is gone")), proto::NBD_EIO);
+    }
+}
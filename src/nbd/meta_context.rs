@@ -0,0 +1,41 @@
+// Registry of the metadata contexts this server can report through NBD_CMD_BLOCK_STATUS.
+// Context ids are positions in `META_CONTEXTS`, starting from 1, so they stay stable across sessions.
+
+pub const BASE_ALLOCATION: u32 = 1;
+
+const META_CONTEXTS: [&str; 1] = [
+    "base:allocation", // BASE_ALLOCATION
+];
+
+pub fn all_contexts() -> Vec<(u32, &'static str)> {
+    META_CONTEXTS
+        .iter()
+        .enumerate()
+        .map(|(i, name)| (i as u32 + 1, *name))
+        .collect()
+}
+
+// Returns the known contexts matching `query`. When `listing`, a bare namespace
+// (i.e. "base:") matches every context in that namespace.
+pub fn match_query(query: &str, listing: bool) -> Vec<(u32, &'static str)> {
+    all_contexts()
+        .into_iter()
+        .filter(|(_, name)| {
+            *name == query || (listing && query.ends_with(':') && name.starts_with(query))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_match_query() {
+        assert_eq!(match_query("base:allocation", false), vec![(BASE_ALLOCATION, "base:allocation")]);
+        assert_eq!(match_query("base:", true), vec![(BASE_ALLOCATION, "base:allocation")]);
+        assert!(match_query("base:", false).is_empty());
+        assert!(match_query("base:alloc", true).is_empty());
+        assert!(match_query("qemu:dirty-bitmap:foo", true).is_empty());
+    }
+}
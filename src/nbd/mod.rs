@@ -1,29 +1,25 @@
 pub mod proto;
 
+mod acl;
+pub use self::acl::{Acl, AclRule, Peer};
+
 mod server;
-pub use self::server::{NBDServer, NBDExport};
+pub use self::server::{NBDServer, NBDExport, OpenExport, ExportOptions, BlockSize, ListenAddr};
+
+mod codec;
+pub use self::codec::{NBDOption, NBDRequest};
+
+mod errno;
+
+mod meta_context;
 
 mod session;
 pub use self::session::NBDSession;
 
-/*
-#[derive(Debug)]
-struct NBDRequest {
-    flags: u16,
-    req_type: u16,
-    handle: u8,
-    offset_h: u32,
-    offset_l: u32,
-    offset: u64,
-    datalen: u32,
-    //data: [u8]
-}
-
-#[derive(Debug)]
-struct NBDOption {
-    option: u32,
-    datalen: u32,
-    name: String,
-    info_reqs: Vec<u16>, //data: [u8]
-}
-*/
+mod stream;
+pub use self::stream::{NBDSocket, NBDStream, NBDReader, NBDWriter};
+
+mod transmission;
+
+mod tls;
+pub use self::tls::TlsConfig;
@@ -9,6 +9,13 @@ pub const NBD_OPT_GO: u32 = 7;
 pub const NBD_OPT_STRUCTURED_REPLY: u32 = 8;
 pub const NBD_OPT_LIST_META_CONTEXT: u32 = 9;
 pub const NBD_OPT_SET_META_CONTEXT: u32 = 10;
+pub const NBD_OPT_EXTENDED_HEADERS: u32 = 11;
+
+pub const NBD_REQUEST_MAGIC: u32 = 0x25609513;
+pub const NBD_EXTENDED_REQUEST_MAGIC: u32 = 0x21e41c71;
+pub const NBD_SIMPLE_REPLY_MAGIC: u32 = 0x67446698;
+pub const NBD_STRUCTURED_REPLY_MAGIC: u32 = 0x668e33ef;
+pub const NBD_EXTENDED_REPLY_MAGIC: u32 = 0x6e8a278c;
 
 pub const NBD_INFO_EXPORT: u16 = 0;
 pub const NBD_INFO_NAME: u16 = 1;
@@ -45,6 +52,7 @@ pub const NBD_REPLY_TYPE_NONE: u16 = 0;
 pub const NBD_REPLY_TYPE_OFFSET_DATA: u16 = 1;
 pub const NBD_REPLY_TYPE_OFFSET_HOLE: u16 = 2;
 pub const NBD_REPLY_TYPE_BLOCK_STATUS: u16 = 5;
+pub const NBD_REPLY_TYPE_BLOCK_STATUS_EXT: u16 = 6;
 pub const NBD_REPLY_TYPE_ERROR: u16 = (1 << 15) + 1;
 pub const NBD_REPLY_TYPE_ERROR_OFFSET: u16 = (1 << 15) + 2;
 
@@ -63,9 +71,10 @@ pub const NBD_REP_ERR_UNKNOWN: u32 = NBD_REP_ERR + 6;
 pub const NBD_REP_ERR_SHUTDOWN: u32 = NBD_REP_ERR + 7;
 pub const NBD_REP_ERR_BLOCK_SIZE_REQD: u32 = NBD_REP_ERR + 8;
 pub const NBD_REP_ERR_TOO_BIG: u32 = NBD_REP_ERR + 9;
+pub const NBD_REP_ERR_EXT_HEADER_REQD: u32 = NBD_REP_ERR + 10;
 
-pub const NBD_STATE_HOLE: u8 = 1 << 0;
-pub const NBD_STATE_ZERO: u8 = 1 << 1;
+pub const NBD_STATE_HOLE: u32 = 1 << 0;
+pub const NBD_STATE_ZERO: u32 = 1 << 1;
 
 // Handshake flag bits
 pub const NBD_FLAG_FIXED_NEWSTYLE: u8 = 1 << 0;
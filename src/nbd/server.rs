@@ -1,41 +1,346 @@
 #![allow(unused_variables)]
 
 use std::{
-    io::{Write, BufWriter},
-    net::{SocketAddr, TcpListener, TcpStream},
-    sync::{Arc, RwLock},
-    rc::Rc,
-    cell::RefCell,
+    collections::HashMap,
+    convert::TryInto,
+    fmt,
+    fs,
+    io,
+    net::{Shutdown, TcpListener, ToSocketAddrs},
+    os::unix::{fs::FileTypeExt, io::{AsRawFd, RawFd}, net::{UnixListener, UnixStream}},
+    path::PathBuf,
+    sync::{Arc, Condvar, Mutex, RwLock, RwLockWriteGuard, atomic::{AtomicBool, AtomicU64, Ordering}, mpsc::{channel, Sender}},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
 use crate::{
-    block::{BlockStorage, BlockStorageConfig, block_storage_with_config},
-    nbd::{proto, NBDSession},
-    util,
+    block::{BlockStorage, BlockStorageConfig, Lease, block_storage_with_config},
+    nbd::{proto, codec, Acl, AclRule, NBDSession, NBDSocket, TlsConfig, transmission::InFlight},
+    util::{self, Propagation},
 };
 
 use log;
 
+// pause after a failed accept(), so running out of file descriptors doesn't spin
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
 pub struct NBDServer {
-    addr: SocketAddr,
-    socket: TcpListener,
-    //sessions: Vec<NBDSession>,
-    host: String,
-    port: u16,
-    exports: Arc<RwLock<Vec<Arc<RwLock<NBDExport>>>>>
+    listeners: Vec<(ListenAddr, Listener)>,
+    // accepting threads, with the socket to stop them with
+    acceptors: Vec<(ListenAddr, RawFd, JoinHandle<()>)>,
+    sessions: Arc<Sessions>,
+    exports: Arc<RwLock<Vec<Arc<RwLock<NBDExport>>>>>,
+    tls_config: Option<Arc<TlsConfig>>
+}
+
+// Address to accept connections on, given as `tcp://HOST:PORT` or `unix:PATH`
+#[derive(Clone, Debug, PartialEq)]
+pub enum ListenAddr {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl ListenAddr {
+    pub fn from_spec(spec: &str) -> Result<ListenAddr, String> {
+        if let Some(addr) = spec.strip_prefix("tcp://") {
+            addr.to_socket_addrs()
+                .map_err(|e| format!("Invalid listen address `{}`: {}", spec, e))?;
+            Ok(ListenAddr::Tcp(addr.to_string()))
+        } else if let Some(path) = spec.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(format!("Invalid listen address `{}`: missing socket path", spec));
+            }
+            Ok(ListenAddr::Unix(PathBuf::from(path)))
+        } else {
+            Err(format!("Invalid listen address `{}`, expected `tcp://HOST:PORT` or `unix:PATH`", spec))
+        }
+    }
+
+    fn bind(&self) -> Result<Listener, io::Error> {
+        match self {
+            ListenAddr::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr)?)),
+            ListenAddr::Unix(path) => {
+                // a socket left behind by a previous run would make bind fail
+                let stale = fs::symlink_metadata(path)
+                    .map(|meta| meta.file_type().is_socket())
+                    .unwrap_or(false);
+                if stale && UnixStream::connect(path).is_err() {
+                    fs::remove_file(path)?;
+                }
+                Ok(Listener::Unix(UnixListener::bind(path)?))
+            }
+        }
+    }
+}
+
+impl Default for ListenAddr {
+    fn default() -> ListenAddr {
+        ListenAddr::Tcp(String::from("0.0.0.0:10809"))
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => f.write_str(&format!("tcp://{}", addr)),
+            ListenAddr::Unix(path) => f.write_str(&format!("unix:{}", path.display())),
+        }
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener) => listener.as_raw_fd(),
+        }
+    }
+
+    // Returns the client socket, and a name for it to show up in logs
+    fn accept(&self) -> Result<(NBDSocket, String), io::Error> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (socket, addr) = listener.accept()?;
+                Ok((NBDSocket::Tcp(socket), addr.to_string()))
+            },
+            Listener::Unix(listener) => {
+                // clients of unix sockets are usually unnamed
+                let (socket, addr) = listener.accept()?;
+                let name = match addr.as_pathname() {
+                    Some(path) => format!("unix:{}", path.display()),
+                    None => String::from("unix"),
+                };
+                Ok((NBDSocket::Unix(socket), name))
+            },
+        }
+    }
+}
+
+// Connections being served, so they can be stopped and waited for on shutdown
+#[derive(Default)]
+struct Sessions {
+    sockets: Mutex<HashMap<u64, NBDSocket>>,
+    last_id: AtomicU64,
+    shutting_down: AtomicBool,
+    closed: Condvar,
+}
+
+// Unregisters the session when dropped, even if the session thread panics
+struct SessionGuard {
+    sessions: Arc<Sessions>,
+    id: u64,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.sessions.sockets.lock().unwrap().remove(&self.id);
+        self.sessions.closed.notify_all();
+    }
+}
+
+impl Sessions {
+    // Returns None if the connection can't be served, i.e. the server is shutting down
+    fn register(self: &Arc<Self>, socket: &NBDSocket) -> Option<SessionGuard> {
+        let mut sockets = self.sockets.lock().unwrap();
+        if self.shutting_down.load(Ordering::SeqCst) {
+            return None
+        }
+        let socket = match socket.try_clone() {
+            Ok(socket) => socket,
+            Err(e) => {
+                log::error!("Couldn't register session: {}", e);
+                return None
+            }
+        };
+        let id = self.last_id.fetch_add(1, Ordering::SeqCst) + 1;
+        sockets.insert(id, socket);
+        Some(SessionGuard { sessions: Arc::clone(self), id })
+    }
+
+    // Stops reading requests, the sessions still reply to the ones they already read
+    fn stop(&self) {
+        let sockets = self.sockets.lock().unwrap();
+        self.shutting_down.store(true, Ordering::SeqCst);
+        for socket in sockets.values() {
+            // fails for sessions that already hung up
+            let _ = socket.shutdown(Shutdown::Read);
+        }
+    }
+
+    // Waits for the sessions to end, and cuts off the ones that are still running at the deadline
+    fn wait(&self, deadline: Instant) -> bool {
+        let mut sockets = self.sockets.lock().unwrap();
+        while !sockets.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                log::error!("{} session(s) still running at the shutdown deadline", sockets.len());
+                for socket in sockets.values() {
+                    let _ = socket.shutdown(Shutdown::Both);
+                }
+                return false
+            }
+            sockets = self.closed.wait_timeout(sockets, deadline - now).unwrap().0;
+        }
+        true
+    }
+}
+
+// Constraints advertised with NBD_INFO_BLOCK_SIZE, requests are checked against them
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BlockSize {
+    pub min: u32,
+    pub preferred: u32,
+    // largest payload of a read or write
+    pub max: u32,
+}
+
+impl Default for BlockSize {
+    fn default() -> BlockSize {
+        BlockSize { min: 512, preferred: 4 * 1024, max: 32 * 1024 * 1024 }
+    }
+}
+
+impl BlockSize {
+    fn validate(&self) -> Result<(), String> {
+        // as required by the spec
+        if !self.min.is_power_of_two() || self.min > 64 * 1024 {
+            return Err(format!("Minimum block size {} must be a power of two up to 64KiB", self.min))
+        }
+        if !self.preferred.is_power_of_two() || self.preferred < self.min.max(512) {
+            return Err(format!("Preferred block size {} must be a power of two, and at least 512 and the minimum block size", self.preferred))
+        }
+        if self.max < self.preferred || self.max % self.min != 0 {
+            return Err(format!("Maximum block size {} must be a multiple of the minimum block size, and at least the preferred one", self.max))
+        }
+        Ok(())
+    }
+}
+
+// Per-export options, given after the export name on the command line, i.e. `mydisk,readonly`
+#[derive(Clone, Default, Debug)]
+pub struct ExportOptions {
+    pub read_only: bool,
+    pub acl: Acl,
+    pub block_size: BlockSize,
+    // shown by NBD_OPT_LIST and NBD_INFO_DESCRIPTION
+    pub description: Option<String>,
+    // sets NBD_FLAG_ROTATIONAL, so clients schedule their requests as for a spinning disk
+    pub rotational: bool,
+    // overrides whether the driver's `supports_trim` is advertised with NBD_FLAG_SEND_TRIM
+    pub trim: Option<bool>,
+    // how long the driver stays open after the last session ends, i.e. to keep caches warm
+    pub linger: Duration,
+}
+
+// Block sizes are given in bytes, or with a binary suffix, i.e. `4Mi`
+fn block_size_from_spec(spec: &str) -> Result<u32, String> {
+    let size = match spec.parse::<u32>() {
+        Ok(size) => size as usize,
+        Err(_) => util::human_size_to_usize(spec).map_err(|_| format!("Invalid block size `{}`", spec))?,
+    };
+    size.try_into().map_err(|_| format!("Block size `{}` is too large", spec))
+}
+
+fn switch_from_spec(option: &str, spec: &str) -> Result<bool, String> {
+    match spec {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(format!("Invalid value `{}` for `{}`, expected `on` or `off`", spec, option)),
+    }
+}
+
+impl ExportOptions {
+    // Splits an export spec into the export name and its options
+    pub fn from_spec(spec: &str) -> Result<(String, ExportOptions), String> {
+        let mut split = spec.split(',');
+        let name = split.next().unwrap_or("").to_string();
+        let mut options = ExportOptions::default();
+        for option in split {
+            match option {
+                "readonly" | "ro" => options.read_only = true,
+                "" => (),
+                _ => match option.split_once('=') {
+                    Some(("allow", rule)) => options.acl.allow.push(AclRule::from_spec(rule)?),
+                    Some(("deny", rule)) => options.acl.deny.push(AclRule::from_spec(rule)?),
+                    Some(("min_block", size)) => options.block_size.min = block_size_from_spec(size)?,
+                    Some(("pref_block", size)) => options.block_size.preferred = block_size_from_spec(size)?,
+                    Some(("max_block", size)) => options.block_size.max = block_size_from_spec(size)?,
+                    Some(("description", description)) => options.description = Some(description.to_string()),
+                    Some(("rotational", value)) => options.rotational = switch_from_spec("rotational", value)?,
+                    Some(("trim", value)) => options.trim = Some(switch_from_spec("trim", value)?),
+                    Some(("linger", seconds)) => options.linger = seconds.parse::<u64>()
+                        .map(Duration::from_secs)
+                        .map_err(|_| format!("Invalid linger `{}`, expected seconds", seconds))?,
+                    _ => return Err(format!("Unknown option `{}` for export `{}`", option, name)),
+                },
+            }
+        }
+        options.block_size.validate().map_err(|e| format!("{} for export `{}`", e, name))?;
+        Ok((name, options))
+    }
 }
 
 pub struct NBDExport {
     pub name: String,
-    size: usize,
+    pub description: Option<String>,
+    pub read_only: bool,
+    pub acl: Acl,
+    pub block_size: BlockSize,
+    pub rotational: bool,
+    // advertised with NBD_FLAG_SEND_TRIM
+    pub trim: bool,
+    pub linger: Duration,
+    // volume size as of when the driver was last closed
+    size: u64,
+    #[allow(dead_code)]
     driver_type: String,
-    driver_config: String,
+    // reopens the driver, exports served from an already opened driver are never closed by sessions
+    config: Option<BlockStorageConfig>,
+    // opened by the first session, and closed after the last one
+    driver: Option<Arc<RwLock<Box<dyn BlockStorage>>>>,
+    // taken out of `driver` while it is flushed and closed, see `close`
+    closing: Option<Arc<RwLock<Box<dyn BlockStorage>>>>,
+    // requests of all connections to the export, see NBD_FLAG_CAN_MULTI_CONN
+    pub in_flight: Arc<InFlight>,
+    // sessions using the driver
+    sessions: usize,
+    // bumped on every open, so a lingering close is dropped once another session came and went
+    generation: u64,
+    lingering: Option<Lingering>,
+    // exclusive write access to the volume, see `new`
+    lease: Option<Arc<Lease>>,
+}
+
+// Closes an unused export after its `linger`, unless it is woken up earlier
+struct Lingering {
+    wake: Sender<()>,
+    // only joined by tests
+    #[cfg_attr(not(test), allow(dead_code))]
+    thread: JoinHandle<()>,
+}
+
+// An export opened by a session, it is released when dropped
+pub struct OpenExport {
+    pub export: Arc<RwLock<NBDExport>>,
     pub driver: Arc<RwLock<Box<dyn BlockStorage>>>,
-    pub in_use: bool
+}
+
+impl Drop for OpenExport {
+    fn drop(&mut self) {
+        NBDExport::release(&self.export);
+    }
 }
 
 impl NBDExport {
-    pub fn new(name: String, driver_type: String, conn_str: String) -> NBDExport {
+    // The volume is leased before it is opened, so a volume served by another server is left
+    // alone; `steal_lease` takes over that server's lease instead. Read-only exports aren't leased.
+    pub fn new(name: String, driver_type: String, conn_str: String, options: ExportOptions, steal_lease: bool) -> Result<NBDExport, io::Error> {
         // TODO: unhardcode below from here (it is okay to hardcode in block/mod.rs though)
         if !["raw", "sharded", "distributed"].contains(&driver_type.as_str()) {
             panic!("Driver must be one of the values `raw` or `sharded`. Found '{}'", driver_type);
@@ -48,100 +353,409 @@ impl NBDExport {
             driver: driver_type.clone(),
             conn_str: conn_str.clone(),
             init_volume: false,
+            read_only: options.read_only,
         };
 
-        let driver = block_storage_with_config(config).unwrap();
-        let size = driver.get_volume_size() as usize;
+        let lease = match options.read_only {
+            true => None,
+            false => Some(Arc::new(Lease::acquire(&config, steal_lease)?)),
+        };
+        // opened once to check the volume and get its size, then again by the first session
+        let driver = block_storage_with_config(config.clone())?;
 
-        log::info!("export {:?} -> {}({:?})", &name, &driver_type, &conn_str);
-        NBDExport {
-            name: name.clone(),
-            size,
+        log::info!("export {:?} -> {}({:?}){}", &name, &driver_type, &conn_str, if options.read_only { " read-only" } else { "" });
+        let mut export = NBDExport {
             driver_type,
-            driver_config: conn_str,
-            driver: Arc::new(RwLock::new(driver)),
-            in_use: false
+            config: Some(config),
+            lease,
+            ..NBDExport::with_driver(name, driver, options)
+        };
+        if let Some(driver) = export.driver.take() {
+            driver.write().unwrap().close();
+        }
+        Ok(export)
+    }
+
+    // Serves an already opened driver, i.e. an in-memory one for tests
+    pub fn with_driver(name: String, driver: Box<dyn BlockStorage>, options: ExportOptions) -> NBDExport {
+        let trim = options.trim.unwrap_or_else(|| driver.supports_trim());
+        if trim && !driver.supports_trim() {
+            log::warn!("export {:?}: `{}` driver can't trim, trims will be ignored", &name, driver.get_name());
+        }
+        NBDExport {
+            name,
+            description: options.description,
+            read_only: options.read_only,
+            acl: options.acl,
+            block_size: options.block_size,
+            rotational: options.rotational,
+            trim,
+            linger: options.linger,
+            size: driver.get_volume_size(),
+            driver_type: driver.get_name(),
+            config: None,
+            driver: Some(Arc::new(RwLock::new(driver))),
+            closing: None,
+            in_flight: Arc::new(InFlight::new()),
+            sessions: 0,
+            generation: 0,
+            lingering: None,
+            lease: None,
+        }
+    }
+
+    pub fn release_lease(&mut self) {
+        self.lease = None;
+    }
+
+    // the export turns read-only once its lease is lost
+    pub fn lease(&self) -> Option<Arc<Lease>> {
+        self.lease.clone()
+    }
+
+    pub fn is_writable(&self) -> bool {
+        !self.read_only && self.lease.as_ref().map_or(true, |lease| lease.is_held())
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.driver.is_some()
+    }
+
+    pub fn volume_size(&self) -> u64 {
+        match &self.driver {
+            Some(driver) => driver.read().unwrap().get_volume_size(),
+            None => self.size,
+        }
+    }
+
+    // Opens the driver unless another session already did, it stays open until every
+    // `OpenExport` is dropped
+    pub fn open(export_ref: &Arc<RwLock<NBDExport>>) -> Result<OpenExport, io::Error> {
+        let mut export = NBDExport::lock_settled(export_ref);
+        let driver = match &export.driver {
+            Some(driver) => Arc::clone(driver),
+            None => {
+                let mut config = match &export.config {
+                    Some(config) => config.clone(),
+                    None => return Err(io::Error::new(io::ErrorKind::NotFound, "Export is closed")),
+                };
+                config.read_only |= !export.is_writable();
+                log::info!("Opening export {}", export.name);
+                let driver = Arc::new(RwLock::new(block_storage_with_config(config)?));
+                if let Some(lease) = &export.lease {
+                    lease.fence_on_loss(&driver);
+                }
+                export.driver = Some(Arc::clone(&driver));
+                driver
+            }
+        };
+        export.sessions += 1;
+        export.generation += 1;
+        // it finds the export in use, once this returns
+        if let Some(lingering) = export.lingering.take() {
+            let _ = lingering.wake.send(());
+        }
+        Ok(OpenExport { export: Arc::clone(export_ref), driver })
+    }
+
+    // Locks the export once its driver isn't being closed anymore, so it is never open twice
+    fn lock_settled(export_ref: &Arc<RwLock<NBDExport>>) -> RwLockWriteGuard<'_, NBDExport> {
+        loop {
+            let export = export_ref.write().unwrap();
+            let closing = match &export.closing {
+                Some(closing) => Arc::clone(closing),
+                None => return export,
+            };
+            drop(export);
+            // held by `close` until the driver is closed
+            drop(closing.write().unwrap());
+            thread::yield_now();
+        }
+    }
+
+    // Closes the driver once the last session is gone, and the export lingered unused
+    fn release(export_ref: &Arc<RwLock<NBDExport>>) {
+        let mut export = export_ref.write().unwrap();
+        export.sessions -= 1;
+        if export.sessions > 0 || export.config.is_none() {
+            return
+        }
+        NBDExport::close_unused(export_ref, export);
+    }
+
+    fn close_unused(export_ref: &Arc<RwLock<NBDExport>>, mut export: RwLockWriteGuard<'_, NBDExport>) {
+        if export.linger.is_zero() {
+            NBDExport::close(export_ref, export);
+            return
+        }
+        let generation = export.generation;
+        let linger = export.linger;
+        let (wake, woken) = channel();
+        log::debug!("Export {} is unused, closing it in {:?}", export.name, linger);
+        let spawned = {
+            let export_ref = Arc::clone(export_ref);
+            thread::Builder::new()
+                .name(format!("linger-{}", export.name))
+                .spawn(move || {
+                    // woken up early, or the linger is over
+                    let _ = woken.recv_timeout(linger);
+                    let export = export_ref.write().unwrap();
+                    if export.sessions == 0 && export.generation == generation {
+                        NBDExport::close(&export_ref, export);
+                    }
+                })
+        };
+        match spawned {
+            Ok(thread) => export.lingering = Some(Lingering { wake, thread }),
+            Err(e) => {
+                log::warn!("Couldn't spawn the linger thread, closing export {} now: {}", export.name, e);
+                NBDExport::close(export_ref, export);
+            }
+        }
+    }
+
+    // Closes a lingering export without waiting for its `linger`
+    #[cfg(test)]
+    fn expire_linger(export_ref: &Arc<RwLock<NBDExport>>) {
+        let lingering = export_ref.write().unwrap().lingering.take();
+        if let Some(lingering) = lingering {
+            let _ = lingering.wake.send(());
+            lingering.thread.join().unwrap();
+        }
+    }
+
+    // Flushes and closes the driver without holding the export, so it can still be listed and
+    // found meanwhile. A driver that couldn't be flushed is put back, the next session reuses it,
+    // and closing it is retried after the next linger or at shutdown.
+    fn close(export_ref: &Arc<RwLock<NBDExport>>, mut export: RwLockWriteGuard<'_, NBDExport>) {
+        let driver_ref = match export.driver.take() {
+            Some(driver_ref) => driver_ref,
+            None => return,
+        };
+        let mut driver = driver_ref.write().unwrap();
+        let size = driver.get_volume_size();
+        export.size = size;
+        export.closing = Some(Arc::clone(&driver_ref));
+        let name = export.name.clone();
+        drop(export);
+
+        let result = driver.flush(0, size as usize);
+        if result.is_ok() {
+            driver.close();
         }
+        drop(driver);
+
+        let mut export = export_ref.write().unwrap();
+        export.closing = None;
+        match result {
+            Ok(propagation) if propagation.is_durable() => log::info!("Closed export {}", name),
+            Ok(propagation) => log::error!("Closed export {}, but it is not durable: {:?}", name, propagation),
+            Err(e) => {
+                log::error!("Couldn't persist export {}, keeping it open: {}", name, e);
+                export.driver = Some(driver_ref);
+                if export.sessions == 0 && !export.linger.is_zero() {
+                    NBDExport::close_unused(export_ref, export);
+                }
+            }
+        }
+    }
+
+    // Makes everything written to the export durable, and closes its driver
+    pub fn persist_and_close(&mut self) -> Result<Propagation, io::Error> {
+        let driver_ref = match &self.driver {
+            Some(driver_ref) => Arc::clone(driver_ref),
+            None => return Ok(Propagation::Guaranteed),
+        };
+        let mut driver = driver_ref.write().unwrap();
+        let size = driver.get_volume_size();
+        let propagation = driver.flush(0, size as usize)?;
+        driver.close();
+        self.size = size;
+        self.driver = None;
+        Ok(propagation)
     }
 }
 
 
 impl NBDServer {
-    pub fn new(host: String, port: u16, exports: Vec<Arc<RwLock<NBDExport>>>) -> NBDServer {
-        let addr: SocketAddr = format!("{}:{}", host, port).parse().unwrap();
-        let socket_addr = addr.clone();
-
-        NBDServer {
-            addr,
-            socket: TcpListener::bind(socket_addr).unwrap(),
-            //sessions: Vec::new(),
-            host,
-            port,
-            exports: Arc::new(RwLock::new(exports))
+    pub fn new(listen: Vec<ListenAddr>, exports: Vec<Arc<RwLock<NBDExport>>>, tls_config: Option<TlsConfig>) -> Result<NBDServer, io::Error> {
+        let mut listeners = Vec::new();
+        for addr in listen {
+            let listener = addr.bind()?;
+            listeners.push((addr, listener));
         }
+
+        Ok(NBDServer {
+            listeners,
+            acceptors: Vec::new(),
+            sessions: Arc::new(Sessions::default()),
+            exports: Arc::new(RwLock::new(exports)),
+            tls_config: tls_config.map(Arc::new)
+        })
     }
 
+    // Accepts connections from every listener, each one in its own thread, until `shutdown`
     pub fn listen(&mut self) {
-        let hostport = format!("{}:{}", self.host, self.port);
-        log::info!("Listening on {}", hostport);
-        // let listener = self.socket;
+        for (addr, listener) in self.listeners.drain(..) {
+            let fd = listener.as_raw_fd();
+            let thread_addr = addr.clone();
+            let sessions = Arc::clone(&self.sessions);
+            let exports = Arc::clone(&self.exports);
+            let tls_config = self.tls_config.clone();
+            let spawned = thread::Builder::new()
+                .name(format!("listener-{}", addr))
+                .spawn(move || NBDServer::accept_connections(thread_addr, listener, sessions, exports, tls_config));
+            match spawned {
+                Ok(acceptor) => self.acceptors.push((addr, fd, acceptor)),
+                Err(e) => log::error!("failed to spawn listener: {:?}", e),
+            }
+        }
+    }
+
+    // Stops accepting connections, lets the sessions finish the requests they already read,
+    // then persists and closes every export. Sessions and exports have `timeout` in total to
+    // finish. Returns false if they didn't, or the exports couldn't be persisted.
+    pub fn shutdown(&mut self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut clean = true;
+
+        self.sessions.stop();
+        for (addr, fd, acceptor) in self.acceptors.drain(..) {
+            // wakes up the blocked accept()
+            unsafe { libc::shutdown(fd, libc::SHUT_RDWR) };
+            if acceptor.join().is_err() {
+                log::error!("Listener on {} panicked", addr);
+            }
+            if let ListenAddr::Unix(path) = &addr {
+                if let Err(e) = fs::remove_file(path) {
+                    log::warn!("Couldn't remove {}: {}", path.display(), e);
+                }
+            }
+        }
+        log::info!("Stopped accepting connections, waiting for the sessions to end");
+        clean &= self.sessions.wait(deadline);
+
+        // Exports are persisted in parallel, and abandoned if they don't make the deadline
+        let (sender, receiver) = channel();
+        let exports = self.exports.read().unwrap().clone();
+        for export in &exports {
+            let export = Arc::clone(export);
+            let sender = sender.clone();
+            thread::spawn(move || {
+                let mut export = NBDExport::lock_settled(&export);
+                let result = export.persist_and_close();
+                // the lease outlives an export that couldn't be persisted, until it expires
+                if matches!(&result, Ok(propagation) if propagation.is_durable()) {
+                    export.release_lease();
+                }
+                // nobody waits for the exports that missed the deadline
+                let _ = sender.send((export.name.clone(), result));
+            });
+        }
+        drop(sender);
+        for _ in 0..exports.len() {
+            let now = Instant::now();
+            let timeout = if deadline > now { deadline - now } else { Duration::ZERO };
+            match receiver.recv_timeout(timeout) {
+                Ok((name, Ok(propagation))) if propagation.is_durable() => {
+                    log::info!("Persisted export {}", name);
+                },
+                Ok((name, Ok(propagation))) => {
+                    log::error!("Export {} is not durable: {:?}", name, propagation);
+                    clean = false;
+                },
+                Ok((name, Err(e))) => {
+                    log::error!("Couldn't persist export {}: {}", name, e);
+                    clean = false;
+                },
+                Err(_) => {
+                    log::error!("Exports couldn't be persisted before the shutdown deadline");
+                    return false
+                },
+            }
+        }
+        clean
+    }
+
+    fn accept_connections(
+        addr: ListenAddr,
+        listener: Listener,
+        sessions: Arc<Sessions>,
+        exports: Arc<RwLock<Vec<Arc<RwLock<NBDExport>>>>>,
+        tls_config: Option<Arc<TlsConfig>>
+    ) {
+        log::info!("Listening on {}", addr);
 
         loop {
             // This part can be simplified with returning a result type and using `?` at the
             // end of .accept()?
-            let (stream, _) = match self.socket.accept() {
+            let (socket, peer) = match listener.accept() {
                 Ok(conn) => conn,
+                Err(_) if sessions.shutting_down.load(Ordering::SeqCst) => break,
+                // i.e. EMFILE or ECONNABORTED, the listener is still usable
                 Err(e) => {
                     log::error!("failed to accept: {:?}", e);
-                    break;
+                    thread::sleep(ACCEPT_BACKOFF);
+                    continue;
                 }
             };
-            let session = self.handle_connection(Rc::new(RefCell::new(stream)));
-            session.handle();
-        }
+            let guard = match sessions.register(&socket) {
+                Some(guard) => guard,
+                None => continue,
+            };
+            log::info!("Accepted connection from {}", peer);
 
-        log::info!("Done");
+            // Every connection gets its own session thread, so a client can't block others
+            let exports = Arc::clone(&exports);
+            let tls_config = tls_config.clone();
+            let spawned = thread::Builder::new()
+                .name(format!("session-{}", peer))
+                .spawn(move || {
+                    let _guard = guard;
+                    match NBDServer::handle_connection(socket, exports, tls_config) {
+                        Ok(session) => session.handle(),
+                        Err(e) => log::warn!("Handshake failed: {}", e),
+                    }
+                });
+            if let Err(e) = spawned {
+                log::error!("failed to spawn session for {}: {:?}", peer, e);
+            }
+        }
+        log::info!("Stopped listening on {}", addr);
     }
 
-    fn handle_connection(&mut self, socket: Rc<RefCell<TcpStream>> /*, addr: SocketAddr*/) -> NBDSession {
-        // TODO: Process socket
-        let flags = self.handshake(Rc::clone(&socket));
+    // Does the handshake, and returns the session that negotiates the rest with the client
+    pub fn handle_connection(
+        mut socket: NBDSocket,
+        exports: Arc<RwLock<Vec<Arc<RwLock<NBDExport>>>>>,
+        tls_config: Option<Arc<TlsConfig>>
+    ) -> Result<NBDSession, io::Error> {
+        let flags = NBDServer::handshake(&mut socket)?;
         // TODO: implement Default for NBDSession
         let session = NBDSession::new(
-            Rc::clone(&socket),
+            socket,
             flags,
             false,
             String::from(""),
             String::from(""),
-            0,
             String::from(""),
-            Arc::clone(&self.exports)
+            exports,
+            tls_config
         );
-        //self.sessions.push(session);
         log::info!("Connection established!");
-        session
+        Ok(session)
     }
 
-    fn handshake(&mut self, socket: Rc<RefCell<TcpStream>>) -> [bool; 2] {
+    fn handshake(socket: &mut NBDSocket) -> Result<[bool; 2], io::Error> {
         log::debug!("Handshake started...");
         let newstyle = proto::NBD_FLAG_FIXED_NEWSTYLE;
         let no_zeroes = proto::NBD_FLAG_NO_ZEROES;
         let handshake_flags = (newstyle | no_zeroes) as u16;
 
-        {
-            let socket = Rc::clone(&socket);
-            let m_socket = &*socket.borrow();
-            let mut buf = BufWriter::new(m_socket);
-            buf.write(b"NBDMAGIC").unwrap();
-            buf.write(b"IHAVEOPT").unwrap();
-            buf.write(&handshake_flags.to_be_bytes()).unwrap();
-            buf.flush().unwrap();
-        }
+        codec::write_handshake(socket, handshake_flags)?;
         log::trace!("Initial message sent");
 
-        let socket = Rc::clone(&socket);
-        let client_flags = util::read_u32(&socket.borrow());
-        drop(socket);
+        let client_flags = codec::read_client_flags(socket)?;
         let flags_list = [
             client_flags & (proto::NBD_FLAG_C_FIXED_NEWSTYLE as u32) != 0,
             client_flags & (proto::NBD_FLAG_C_NO_ZEROES as u32) != 0,
@@ -150,6 +764,153 @@ impl NBDServer {
         log::debug!(" -> fixedNewStyle: {}", flags_list[0]);
         log::debug!(" -> noZeroes: {}", flags_list[1]);
         log::debug!("Handshake done successfully!");
-        flags_list
+        Ok(flags_list)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{path::Path, sync::mpsc::Receiver};
+    use crate::util::test_utils::TempFolder;
+
+    // its flushes wait for the test to tell whether they succeed
+    struct ControlledFlush {
+        started: Mutex<Sender<()>>,
+        results: Mutex<Receiver<bool>>,
+    }
+
+    impl BlockStorage for ControlledFlush {
+        fn init(&mut self, _init_volume: bool) -> Result<(), Box<dyn std::error::Error>> { Ok(()) }
+        fn init_volume(&mut self) -> Result<(), Box<dyn std::error::Error>> { Ok(()) }
+        fn check_volume(&mut self) -> Result<(), Box<dyn std::error::Error>> { Ok(()) }
+        fn destroy_volume(&mut self) {}
+        fn get_name(&self) -> String { String::from("controlled") }
+        fn get_volume_size(&self) -> u64 { 1 << 20 }
+
+        fn read(&self, _offset: u64, length: usize) -> Result<Vec<u8>, io::Error> {
+            Ok(vec![0; length])
+        }
+
+        fn write(&mut self, _offset: u64, _length: usize, _data: &[u8]) -> Result<Propagation, io::Error> {
+            Ok(Propagation::Guaranteed)
+        }
+
+        fn flush(&self, _offset: u64, _length: usize) -> Result<Propagation, io::Error> {
+            self.started.lock().unwrap().send(()).unwrap();
+            match self.results.lock().unwrap().recv().unwrap() {
+                true => Ok(Propagation::Guaranteed),
+                false => Err(io::Error::new(io::ErrorKind::Other, "backend is down")),
+            }
+        }
+
+        fn close(&mut self) {}
+    }
+
+    #[test]
+    fn test_listen_addr_from_spec() {
+        assert_eq!(ListenAddr::from_spec("tcp://127.0.0.1:10809"), Ok(ListenAddr::Tcp(String::from("127.0.0.1:10809"))));
+        assert_eq!(ListenAddr::from_spec("tcp://[::1]:10810"), Ok(ListenAddr::Tcp(String::from("[::1]:10810"))));
+        assert_eq!(ListenAddr::from_spec("unix:/run/nbd-rs.sock"), Ok(ListenAddr::Unix(PathBuf::from("/run/nbd-rs.sock"))));
+        assert!(ListenAddr::from_spec("tcp://[::1]").is_err());
+        assert!(ListenAddr::from_spec("unix:").is_err());
+        assert!(ListenAddr::from_spec("127.0.0.1:10809").is_err());
+        assert_eq!(ListenAddr::default().to_string(), "tcp://0.0.0.0:10809");
+    }
+
+    #[test]
+    fn test_export_options_from_spec() {
+        let (name, options) = ExportOptions::from_spec("mydisk,ro,allow=10.0.0.0/8,allow=uid:1000,deny=10.0.0.5").unwrap();
+        assert_eq!(name, "mydisk");
+        assert!(options.read_only);
+        assert_eq!(options.acl.allow, vec![AclRule::Cidr("10.0.0.0".parse().unwrap(), 8), AclRule::Uid(1000)]);
+        assert_eq!(options.acl.deny, vec![AclRule::Cidr("10.0.0.5".parse().unwrap(), 32)]);
+        assert_eq!(options.block_size, BlockSize::default());
+        assert_eq!((options.description, options.rotational, options.trim), (None, false, None));
+        assert!(ExportOptions::from_spec("mydisk,allow=example.com").is_err());
+        assert!(ExportOptions::from_spec("mydisk,readwrite").is_err());
+
+        let (_, options) = ExportOptions::from_spec("mydisk,min_block=4096,pref_block=4Mi,max_block=64Mi").unwrap();
+        assert_eq!(options.block_size, BlockSize { min: 4096, preferred: 4 * 1024 * 1024, max: 64 * 1024 * 1024 });
+        assert!(ExportOptions::from_spec("mydisk,min_block=1000").is_err());
+        assert!(ExportOptions::from_spec("mydisk,min_block=131072").is_err());
+        assert!(ExportOptions::from_spec("mydisk,pref_block=64Mi").is_err());
+        assert!(ExportOptions::from_spec("mydisk,max_block=8Gi").is_err());
+
+        let (_, options) = ExportOptions::from_spec("mydisk,description=Debian 12 installer,rotational=on,trim=off").unwrap();
+        assert_eq!(options.description.as_deref(), Some("Debian 12 installer"));
+        assert!(options.rotational);
+        assert_eq!(options.trim, Some(false));
+        assert!(ExportOptions::from_spec("mydisk,trim=yes").is_err());
+        assert_eq!(ExportOptions::from_spec("mydisk,linger=30").unwrap().1.linger, Duration::from_secs(30));
+        assert!(ExportOptions::from_spec("mydisk,linger=-1").is_err());
+    }
+
+    #[test]
+    fn test_export_lifecycle() {
+        let folder = TempFolder::new();
+        fs::write(Path::new(&folder.path).join("size"), "16777216").unwrap();
+        let export = NBDExport::new(String::from("disk"), String::from("sharded"), format!("file:///{}", folder.path), ExportOptions::default(), false).unwrap();
+        let export = Arc::new(RwLock::new(export));
+        assert!(!export.read().unwrap().is_open());
+        assert_eq!(export.read().unwrap().volume_size(), 16777216);
+
+        // sessions share the driver, the last one closes it
+        let first = NBDExport::open(&export).unwrap();
+        let second = NBDExport::open(&export).unwrap();
+        assert!(Arc::ptr_eq(&first.driver, &second.driver));
+        drop(first);
+        assert!(export.read().unwrap().is_open());
+        drop(second);
+        assert!(!export.read().unwrap().is_open());
+
+        // lingering exports are closed later, unless a session came in the meantime
+        export.write().unwrap().linger = Duration::from_secs(3600);
+        drop(NBDExport::open(&export).unwrap());
+        assert!(export.read().unwrap().is_open());
+        let third = NBDExport::open(&export).unwrap();
+        NBDExport::expire_linger(&export);
+        assert!(export.read().unwrap().is_open());
+        drop(third);
+        assert!(export.read().unwrap().is_open());
+        NBDExport::expire_linger(&export);
+        assert!(!export.read().unwrap().is_open());
+    }
+
+    #[test]
+    fn test_export_close_outside_lock() {
+        let (started, flush_started) = channel();
+        let (flush_result, results) = channel();
+        let driver = Box::new(ControlledFlush { started: Mutex::new(started), results: Mutex::new(results) });
+        let mut export = NBDExport::with_driver(String::from("disk"), driver, ExportOptions::default());
+        export.config = Some(BlockStorageConfig {
+            export_name: Some(String::from("disk")),
+            export_size: None,
+            export_force: false,
+            driver: String::from("sharded"),
+            conn_str: String::from("file:///nonexistent"),
+            init_volume: false,
+            read_only: false,
+        });
+        let export = Arc::new(RwLock::new(export));
+
+        // the export can be looked up while the last session closes it
+        let session = NBDExport::open(&export).unwrap();
+        let driver = Arc::clone(&session.driver);
+        let closing = thread::spawn(move || drop(session));
+        flush_started.recv().unwrap();
+        assert_eq!(export.read().unwrap().volume_size(), 1 << 20);
+        // a driver that couldn't be flushed is kept, and reused by the next session
+        flush_result.send(false).unwrap();
+        closing.join().unwrap();
+        assert!(export.read().unwrap().is_open());
+        let session = NBDExport::open(&export).unwrap();
+        assert!(Arc::ptr_eq(&session.driver, &driver));
+
+        let closing = thread::spawn(move || drop(session));
+        flush_started.recv().unwrap();
+        flush_result.send(true).unwrap();
+        closing.join().unwrap();
+        assert!(!export.read().unwrap().is_open());
     }
 }
@@ -1,30 +1,44 @@
 #![allow(unused_variables, unused_imports)]
 
 use std::{
-    io::{Read, Write, BufWriter},
-    net::{TcpStream},
-    time::{SystemTime, UNIX_EPOCH},
+    io::{Error, ErrorKind},
+    net::Shutdown,
     sync::{Arc, RwLock},
-    rc::Rc,
     cell::{RefCell, Cell},
 };
 
 use crate::{
     block::{BlockStorage, block_storage_with_config},
-    util,
-    nbd::{proto, server}
+    nbd::{proto, server, meta_context, BlockSize, OpenExport, Peer,
+          codec::{self, NBDOption, OptionData, InfoRequest, MetaContextRequest}, NBDSocket, NBDStream, TlsConfig, transmission::{Transmission, TransmissionOptions, InFlight}}
 };
 
+// Phases of a session, the handshake is done by the server before the session is created
+pub enum Phase {
+    // options are read and replied to, until NBD_OPT_GO or NBD_OPT_EXPORT_NAME succeeds
+    Options,
+    // the export is bound, and requests are served until the client disconnects
+    Transmission(OpenExport),
+    // the client aborted, or the connection can't continue
+    Closed,
+}
 
 pub struct NBDSession {
-    pub socket: Rc<RefCell<TcpStream>>,
+    pub socket: RefCell<NBDStream>,
     pub flags: [bool; 2],
+    pub tls_config: Option<Arc<TlsConfig>>,
+    // who is on the other end, for the export ACLs
+    pub peer: Peer,
     pub structured_reply: Cell<bool>,
-    pub selected_export: RefCell<Option<Arc<RwLock<server::NBDExport>>>>,
+    pub extended_headers: Cell<bool>,
+    pub phase: RefCell<Phase>,
     pub driver_name: String,
     pub export_refs: Arc<RwLock<Vec<Arc<RwLock<server::NBDExport>>>>>,
-    // TODO: contexts: list of active contexts with attached metadata_context_ids
-    pub metadata_context_id: Cell<u32>,
+    // ids of the active metadata contexts, and the export they were negotiated for
+    pub meta_contexts: RefCell<Vec<u32>>,
+    pub meta_context_export: RefCell<String>,
+    // whether NBD_OPT_GO asked for NBD_INFO_BLOCK_SIZE, the minimum block size is enforced then
+    pub block_size_requested: Cell<bool>,
     //request: Option<NBDRequest>,
     //option: Option<NBDOption>, // addr: SocketAddr,
                                // socket
@@ -34,650 +48,472 @@ pub struct NBDSession {
 
 impl NBDSession {
     pub fn new(
-        socket: Rc<RefCell<TcpStream>>,
+        socket: NBDSocket,
         flags: [bool; 2],
         structured_reply: bool,
         driver_name: String,
         image_name: String,
-        metadata_context_id: u32,
         storage_config: String,
-        export_refs: Arc<RwLock<Vec<Arc<RwLock<server::NBDExport>>>>>
+        export_refs: Arc<RwLock<Vec<Arc<RwLock<server::NBDExport>>>>>,
+        tls_config: Option<Arc<TlsConfig>>
     ) -> NBDSession {
+        let peer = Peer::of(&socket);
         NBDSession {
-            socket: socket,
+            socket: RefCell::new(NBDStream::Plain(socket)),
             flags: flags,
+            tls_config: tls_config,
+            peer,
             structured_reply: Cell::new(structured_reply),
-            selected_export: RefCell::new(None),
-            metadata_context_id: Cell::new(metadata_context_id),
+            extended_headers: Cell::new(false),
+            phase: RefCell::new(Phase::Options),
+            meta_contexts: RefCell::new(Vec::new()),
+            meta_context_export: RefCell::new(String::new()),
+            block_size_requested: Cell::new(false),
             driver_name: driver_name.clone(),
             export_refs: export_refs
         }
     }
 
-    pub fn handle(&self) {
-        log::debug!("Transmission");
-        loop {
-            let socket = Rc::clone(&self.socket);
-            let req = match util::read_u32(&socket.borrow()) {
-                0x25609513 => 0x25609513, // NBD_REQUEST_MAGIC
-                0x49484156 => match util::read_u32(&socket.borrow()) {
-                    // "IHAV"
-                    0x454F5054 => 0x49484156454F5054 as u64, // "IHAV + EOPT"
-                    e => {
-                        log::error!(
-                            "Error at NBD_IHAVEOPT. Expected: 0x49484156454F5054 Got: {:?}",
-                            format!("{:#X}", e)
-                        );
-                        break;
-                    }
-                },
-                0x0 => {
+    pub fn handle(self) {
+        log::debug!("Negotiation");
+        while self.negotiating() {
+            let option = NBDOption::read(&mut *self.socket.borrow_mut());
+            let option = match option {
+                Ok(option) => option,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
                     log::info!("Terminating connection.");
                     break;
                 },
-                e => {
-                    log::error!(
-                        "Error at NBD_REQUEST_MAGIC. Expected: 0x25609513 Got: {:?}",
-                        format!("{:#X}", e)
-                    );
+                Err(e) => {
+                    log::error!("{}", e);
                     break;
                 }
             };
-            drop(socket);
-            match req {
-                0x25609513 => {
-                    // NBD_REQUEST_MAGIC
-                    self.handle_request();
-                }
-                0x49484156454F5054 => {
-                    // IHAVEOPT
-                    self.handle_option();
-                }
-                _ => (),
+            if let Err(e) = self.handle_option(option) {
+                log::error!("Negotiation failed: {}", e);
+                break;
             }
         }
-        log::info!("Transmission ended");
-        let selected_export = self.selected_export.borrow_mut();
-        if selected_export.is_some() {
-            let mut write_lock = selected_export.as_ref().unwrap().try_write().unwrap();
-            {
-                let mut driver = Arc::get_mut(&mut write_lock.driver).unwrap().try_write().unwrap();
-                driver.close();
-            }
-            drop(write_lock);
+
+        // only a bound export moves on to transmission
+        let phase = self.phase.replace(Phase::Closed);
+        if let Phase::Transmission(export) = phase {
+            self.transmit(export);
         }
     }
-    fn handle_request(&self) {
-        let socket = Rc::clone(&self.socket);
-        let m_socket = socket.borrow();
-        let flags = util::read_u16(&m_socket);
-        let req_type = util::read_u16(&m_socket);
-        let handle = util::read_u64(&m_socket);
-        let offset = util::read_u64(&m_socket);
-        let datalen = util::read_u32(&m_socket);
-        drop(m_socket);
-        drop(socket);
-        match req_type {
-            proto::NBD_CMD_READ => { // 0
-                log::debug!("NBD_CMD_READ");
-                log::trace!("\t-->flags:{}, handle: {}, offset: {}, datalen: {}", flags, handle, offset, datalen);
-                log::trace!("STRUCTURED REPLY: {}", self.structured_reply.get());
-                let selected_export = self.selected_export.borrow_mut();
-                let mut write_lock = selected_export.as_ref().unwrap().try_write().unwrap();
-                let driver = Arc::get_mut(&mut write_lock.driver).unwrap().try_write().unwrap();
-                let buffer_res = driver.read(offset, datalen as usize);
-                if buffer_res.is_err() {
-                    // handle error
-                    let err = buffer_res.err().unwrap().to_string();
-                    log::warn!("NBD_CMD_READ failed: {}", err.clone());
-                    let err_msg = err.as_bytes();
-                    if self.structured_reply.get() == true {
-                        self.structured_reply(
-                            proto::NBD_REPLY_FLAG_DONE,
-                            proto::NBD_REPLY_TYPE_ERROR,
-                            handle,
-                            6 + err_msg.len() as u32
-                        );
-                        {
-                            let socket = Rc::clone(&self.socket);
-                            let mut m_socket = socket.borrow_mut();
-                            util::write_u32(proto::NBD_REP_ERR_UNKNOWN, &mut m_socket);
-                            util::write_u16(err_msg.len() as u16, &mut m_socket);
-                            write!(err_msg, &mut m_socket);
-                        }
-                    } else {
-                        self.simple_reply(
-                            proto::NBD_REP_ERR_UNKNOWN,
-                            handle
-                        );
-                    }
-                } else {
-                    log::trace!("NBD_CMD_READ ok!");
-                    if self.structured_reply.get() == true {
-                        self.structured_reply(
-                            proto::NBD_REPLY_FLAG_DONE,
-                            proto::NBD_REPLY_TYPE_OFFSET_DATA,
-                            handle,
-                            8 + datalen
-                        );
-                        {
-                            let socket = Rc::clone(&self.socket);
-                            util::write_u64(offset, &mut socket.borrow_mut());
-                        }
-                    } else {
-                        self.simple_reply(0_u32, handle);
-                    }
-                    let socket = Rc::clone(&self.socket);
-                    socket.borrow_mut().write(&buffer_res.unwrap()).expect("Couldn't send data.");
-                }
-            }
-            proto::NBD_CMD_WRITE => { // 1
-                log::debug!("NBD_CMD_WRITE");
-                log::trace!("\t-->flags:{}, handle: {}, offset: {}, datalen: {}", flags, handle, offset, datalen);
-                let mut data = vec![0; datalen as usize];
-                let read_result: Result<_, _>;
-                {
-                    let socket = Rc::clone(&self.socket);
-                    read_result = socket.borrow_mut().read_exact(&mut data);
-                }
-                match read_result {
-                    Ok(_) => {
-                        let selected_export = self.selected_export.borrow_mut();
-                        let mut write_lock = selected_export.as_ref().unwrap().try_write().unwrap();
-                        let mut driver = Arc::get_mut(&mut write_lock.driver).unwrap().try_write().unwrap();
-                        let driver_name = driver.get_name();
-
-                        let write_res = driver.write(offset, datalen as usize, &data);
-                        if write_res.is_err() {
-                            // handle error
-                            let err = write_res.err().unwrap().to_string();
-                            log::warn!("NBD_CMD_WRITE failed: {}", err.clone());
-                            let err_msg = err.as_bytes();
-                            if self.structured_reply.get() == true {
-                                self.structured_reply(
-                                    proto::NBD_REPLY_FLAG_DONE,
-                                    proto::NBD_REPLY_TYPE_ERROR,
-                                    handle,
-                                    6 + err_msg.len() as u32
-                                );
-                                {
-                                    let socket = Rc::clone(&self.socket);
-                                    let m_socket = &*socket.borrow();
-                                    let mut buf = BufWriter::new(m_socket);
-                                    buf.write(&proto::NBD_REP_ERR_UNKNOWN.to_be_bytes()).unwrap();
-                                    buf.write(&(err_msg.len() as u16).to_be_bytes()).unwrap();
-                                    buf.write(err_msg).unwrap();
-                                    buf.flush().unwrap();
-                                }
-                            } else {
-                                self.simple_reply(
-                                    proto::NBD_REP_ERR_UNKNOWN,
-                                    handle
-                                );
-                            }
-                        } else {
-                            log::trace!("NBD_CMD_WRITE ok!");
-
-                            if self.structured_reply.get() == true {
-                                self.structured_reply(
-                                    proto::NBD_REPLY_FLAG_DONE,
-                                    proto::NBD_REPLY_TYPE_NONE,
-                                    handle,
-                                    0
-                                );
-                            } else {
-                                self.simple_reply(
-                                    0_u32,
-                                    handle
-                                );
-                            }
-                        }
-                    },
-                    Err(e) => {
-                        log::error!("{}", e);
-                        if self.structured_reply.get() == true {
-                            let err_msg = b"Could not receive the data. Please try again later";
-                            self.structured_reply(
-                                proto::NBD_REPLY_FLAG_DONE,
-                                proto::NBD_REPLY_TYPE_ERROR,
-                                handle,
-                                6 + err_msg.len() as u32
-                            );
-                            {
-                                let socket = Rc::clone(&self.socket);
-                                let m_socket = &*socket.borrow();
-                                let mut buf = BufWriter::new(m_socket);
-                                buf.write(&proto::NBD_REP_ERR_UNKNOWN.to_be_bytes()).unwrap();
-                                buf.write(&(err_msg.len() as u16).to_be_bytes()).unwrap();
-                                buf.write(err_msg).unwrap();
-                                buf.flush().unwrap();
-                            }
-                        } else {
-                            self.simple_reply(
-                                proto::NBD_REP_ERR_UNKNOWN,
-                                handle
-                            );
-                        }
-                    }
-                }
-            }
-            proto::NBD_CMD_DISC => { // 2
-                // Terminate TLS
-                log::debug!("NBD_CMD_DISC");
-                let selected_export = self.selected_export.borrow_mut();
-                if selected_export.is_some() {
-                    let mut write_lock = selected_export.as_ref().unwrap().try_write().unwrap();
-                    {
-                        let mut driver = Arc::get_mut(&mut write_lock.driver).unwrap().try_write().unwrap();
-                        driver.close();
-                    }
-                    drop(write_lock);
-                }
-            }
-            proto::NBD_CMD_FLUSH => { // 3
-                log::debug!("NBD_CMD_FLUSH");
-                log::trace!("\t-->flags:{}, handle: {}, offset: {}, datalen: {}", flags, handle, offset, datalen);
-                if datalen == 0 {
-                    log::warn!("Flush length is zero. Ignoring flush");
-                } else {
-                    let selected_export = self.selected_export.borrow_mut();
-                    let mut write_lock = selected_export.as_ref().unwrap().try_write().unwrap();
-                    {
-                        let mut driver = Arc::get_mut(&mut write_lock.driver).unwrap().try_write().unwrap();
-                        let driver_name = driver.get_name();
-                        let volume_size = driver.get_volume_size() as usize;
-                        match driver.flush(0, volume_size) {
-                            Ok(_) => log::trace!("flushed"),
-                            Err(e) => log::error!("{}", e) // TODO: Reflect error to client
-                        }
-                    }
-                }
-                if self.structured_reply.get() == true {
-                    self.structured_reply(
-                        proto::NBD_REPLY_FLAG_DONE,
-                        proto::NBD_REPLY_TYPE_NONE,
-                        handle,
-                        0
-                    );
-                } else {
-                    self.simple_reply(0_u32, handle);
-                }
-            }
-            proto::NBD_CMD_TRIM => { // 4
-                log::debug!("NBD_CMD_TRIM");
-                let selected_export = self.selected_export.borrow_mut();
-                let mut write_lock = selected_export.as_ref().unwrap().try_write().unwrap();
-                {
-                    let mut driver = Arc::get_mut(&mut write_lock.driver).unwrap().try_write().unwrap();
-                    let driver_name = driver.get_name();
-                    let volume_size = driver.get_volume_size() as usize;
-                    log::trace!("offset: {}, length: {}", offset, datalen);
-                    match driver.trim(offset, datalen as usize) {
-                        Ok(_) => log::trace!("trimmed"),
-                        Err(e) => log::error!("{}", e) // TODO: Reflect error to client
-                    }
-                }
-                if self.structured_reply.get() == true {
-                    self.structured_reply(
-                        proto::NBD_REPLY_FLAG_DONE,
-                        proto::NBD_REPLY_TYPE_NONE,
-                        handle,
-                        0
-                    );
-                } else {
-                    self.simple_reply(0_u32, handle);
-                }
-            }
-            proto::NBD_CMD_BLOCK_STATUS => { // 7
-                // fsync
-                log::debug!("NBD_CMD_BLOCK_STATUS");
-                let single_extent_only = (flags & proto::NBD_CMD_FLAG_REQ_ONE) != 0;
-                log::trace!("\t-->flags:{}, handle: {}, offset: {}, datalen: {}", flags, handle, offset, datalen);
-                self.structured_reply(
-                    proto::NBD_REPLY_FLAG_DONE,
-                    proto::NBD_REPLY_TYPE_BLOCK_STATUS,
-                    handle,
-                    12 //datalen
-                );
-                {
-                    let socket = Rc::clone(&self.socket);
-                    let m_socket = &*socket.borrow();
-                    let mut buf = BufWriter::new(m_socket);
-                    buf.write(&self.metadata_context_id.get().to_be_bytes()).unwrap();
-                    buf.write(&datalen.to_be_bytes()).unwrap();
-                    buf.write(&0_u32.to_be_bytes()).unwrap();
-                    buf.flush().unwrap();
-                }
-            }
-            _ => {
-                log::warn!("Invalid/Unimplemented CMD: {:?}", req_type);
-                self.simple_reply(proto::NBD_REP_ERR_UNSUP, handle);
-            }
+
+    // The export is released once the transmission ends, and closed if no other session uses it
+    fn transmit(self, open_export: OpenExport) {
+        log::debug!("Transmission");
+        let export = open_export.export.read().unwrap();
+        let transmission = Transmission::new(
+            Arc::clone(&open_export.driver),
+            Arc::clone(&export.in_flight),
+            TransmissionOptions {
+                read_only: export.read_only,
+                lease: export.lease(),
+                structured_reply: self.structured_reply.get(),
+                extended_headers: self.extended_headers.get(),
+                meta_contexts: self.meta_contexts.borrow().clone(),
+                block_size: export.block_size,
+                aligned: self.block_size_requested.get(),
+            },
+        );
+        drop(export);
+        match self.socket.into_inner().split() {
+            Ok((reader, writer)) => if let Err(e) = Arc::new(transmission).serve(reader, writer) {
+                log::error!("Couldn't serve the connection: {}", e);
+            },
+            Err(e) => log::error!("Couldn't split the connection: {}", e),
         }
+        log::info!("Transmission ended");
     }
 
-    fn simple_reply(&self, err_code: u32, handle: u64) {
-        let socket = Rc::clone(&self.socket);
-        let m_socket = &*socket.borrow();
-        let mut buf = BufWriter::new(m_socket);
-        buf.write(&0x67446698_u32.to_be_bytes()).unwrap();
-        buf.write(&err_code.to_be_bytes()).unwrap();
-        buf.write(&handle.to_be_bytes()).unwrap();
-        buf.flush().unwrap();
+    fn negotiating(&self) -> bool {
+        matches!(*self.phase.borrow(), Phase::Options)
     }
 
-    fn structured_reply(&self, flags: u16, reply_type: u16, handle: u64, length_of_payload: u32) {
-        let socket = Rc::clone(&self.socket);
-        let m_socket = &*socket.borrow();
-        let mut buf = BufWriter::new(m_socket);
-        buf.write(&0x668e33ef_u32.to_be_bytes()).unwrap();
-        buf.write(&flags.to_be_bytes()).unwrap();
-        buf.write(&reply_type.to_be_bytes()).unwrap();
-        buf.write(&handle.to_be_bytes()).unwrap();
-        buf.write(&length_of_payload.to_be_bytes()).unwrap();
-        buf.flush().unwrap();
+    // Ends the session without transmission, i.e. after NBD_OPT_ABORT
+    fn close(&self) {
+        let _ = self.socket.borrow().shutdown(Shutdown::Both);
+        self.phase.replace(Phase::Closed);
     }
 
-    fn handle_option(&self) {
-        let socket = Rc::clone(&self.socket);
-        let option = util::read_u32(&socket.borrow());
-        drop(socket);
-        log::debug!("Option: {}", option);
+    fn handle_option(&self, option: NBDOption) -> Result<(), Error> {
+        if option.too_big() {
+            log::warn!("Refusing OPT {:?} with {} bytes of data", option.option, option.length);
+            if option.option == proto::NBD_OPT_EXPORT_NAME {
+                return Err(Error::new(ErrorKind::InvalidData, "Export name is too long"))
+            }
+            return self.reply(option.option, proto::NBD_REP_ERR_TOO_BIG, &[])
+        }
+        let NBDOption { option, data, .. } = option;
+        log::debug!("Option: {}, length: {}", option, data.len());
+        if self.tls_pending(option) {
+            log::warn!("TLS is required, refusing OPT: {:?}", option);
+            if option == proto::NBD_OPT_EXPORT_NAME {
+                // can't reply to this one, terminate instead
+                self.close();
+                return Ok(())
+            }
+            return self.reply(option, proto::NBD_REP_ERR_TLS_REQD, &[])
+        }
         match option {
             proto::NBD_OPT_ABORT => {// 2
-                self.handle_opt_abort();
-                //println!("Shutting down connection...")'
-                //self.socket.shutdown(Shutdown::Both).expect("Shutdown failed");
-                //break;
+                log::info!("Client aborted negotiation");
+                self.reply(option, proto::NBD_REP_ACK, &[])?;
+                self.close();
+            }
+            proto::NBD_OPT_EXPORT_NAME => {// 1
+                self.handle_opt_export_name(&data)?;
+            }
+            proto::NBD_OPT_LIST => {// 3
+                self.handle_opt_list(&data)?;
+            }
+            proto::NBD_OPT_STARTTLS => {// 5
+                self.handle_opt_starttls(&data)?;
             }
             proto::NBD_OPT_INFO | proto::NBD_OPT_GO => {// 6, 7
-                self.handle_opt_info_go(option);
+                self.handle_opt_info_go(option, &data)?;
             }
             proto::NBD_OPT_STRUCTURED_REPLY => {// 8
-                let socket = Rc::clone(&self.socket);
-                let data = util::read_u32(&socket.borrow());
-                drop(socket);
-                if data > 0 {
-                    log::trace!("{}", data);
-                    self.reply(
-                        proto::NBD_OPT_STRUCTURED_REPLY,
-                        proto::NBD_REP_ERR_INVALID,
-                        0
-                    );
+                if self.extended_headers.get() {
+                    // extended headers already imply structured replies
+                    self.reply(option, proto::NBD_REP_ERR_EXT_HEADER_REQD, &[])?;
+                } else if !data.is_empty() {
+                    self.reply(option, proto::NBD_REP_ERR_INVALID, &[])?;
                 } else {
-                    self.reply(
-                        proto::NBD_OPT_STRUCTURED_REPLY,
-                        proto::NBD_REP_ACK,
-                        0
-                    );
+                    self.reply(option, proto::NBD_REP_ACK, &[])?;
                     self.structured_reply.set(true);
+                    self.reset_meta_contexts();
                 }
             }
-            proto::NBD_OPT_SET_META_CONTEXT => {// 10
-                self.handle_opt_set_meta_context();
+            proto::NBD_OPT_LIST_META_CONTEXT | proto::NBD_OPT_SET_META_CONTEXT => {// 9, 10
+                self.handle_opt_meta_context(option, &data)?;
+            }
+            proto::NBD_OPT_EXTENDED_HEADERS => {// 11
+                if !data.is_empty() {
+                    self.reply(option, proto::NBD_REP_ERR_INVALID, &[])?;
+                } else {
+                    self.reply(option, proto::NBD_REP_ACK, &[])?;
+                    self.extended_headers.set(true);
+                    self.structured_reply.set(true);
+                    self.reset_meta_contexts();
+                }
             }
             _ => {
                 log::warn!("Invalid/Unimplemented OPT: {:?}", option);
-                self.reply_opt(
-                    option,
-                    proto::NBD_REP_ERR_UNSUP,
-                    0,
-                );
+                self.reply(option, proto::NBD_REP_ERR_UNSUP, &[])?;
             }
         }
+        Ok(())
     }
 
-    fn reply(&self, opt: u32, reply_type: u32, len: u32) {
-        let socket = Rc::clone(&self.socket);
-        let m_socket = &*socket.borrow();
-        let mut buf = BufWriter::new(m_socket);
-        buf.write(&0x3e889045565a9_u64.to_be_bytes()).unwrap(); // Reply Magic
-        buf.write(&opt.to_be_bytes()).unwrap();
-        buf.write(&reply_type.to_be_bytes()).unwrap();
-        buf.write(&len.to_be_bytes()).unwrap();
-        buf.flush().unwrap();
+    fn reply(&self, opt: u32, reply_type: u32, data: &[u8]) -> Result<(), Error> {
+        if !self.negotiating() {
+            // the client already moved on, it would read this as a transmission reply
+            return Err(Error::new(ErrorKind::Other, format!("Option reply to OPT {} after negotiation", opt)))
+        }
+        codec::write_option_reply(&mut *self.socket.borrow_mut(), opt, reply_type, data)
     }
 
-    fn reply_info_export(&self, opt: u32, export_name: String) {
-        self.reply(
-            opt,
-            proto::NBD_REP_INFO,
-            12
-        );
-        let mut flags: u16 = proto::NBD_FLAG_HAS_FLAGS | proto::NBD_FLAG_SEND_FLUSH | proto::NBD_FLAG_SEND_RESIZE | proto::NBD_FLAG_SEND_CACHE;
-        let volume_size: u64;
-        let selected_export = self.selected_export.borrow_mut();
-        if selected_export.is_none() {
-            self.select_export(export_name);
-        }
-        let mut write_lock = selected_export.as_ref().unwrap().try_write().unwrap();
-        {
-            let driver = Arc::get_mut(&mut write_lock.driver).unwrap().try_write().unwrap();
-            let driver_name = driver.get_name();
-            volume_size = driver.get_volume_size();
+    // Contexts are negotiated for a reply format, switching to another one forgets them
+    fn reset_meta_contexts(&self) {
+        self.meta_contexts.borrow_mut().clear();
+        self.meta_context_export.borrow_mut().clear();
+    }
 
-            if driver.supports_trim() {
-                flags |= proto::NBD_FLAG_SEND_TRIM;
-            }
-        }
-        {
-            let socket = Rc::clone(&self.socket);
-            let m_socket = &*socket.borrow();
-            let mut buf = BufWriter::new(m_socket);
-            buf.write(&proto::NBD_INFO_EXPORT.to_be_bytes()).unwrap();
-            buf.write(&volume_size.to_be_bytes()).unwrap();
-            buf.write(&flags.to_be_bytes()).unwrap();
-            buf.flush().unwrap();
-        }
+    // Refuses an option with a message for the user
+    fn reply_error(&self, opt: u32, reply_type: u32, message: &str) -> Result<(), Error> {
+        self.reply(opt, reply_type, message.as_bytes())
+    }
+
+    fn reply_info_export(&self, opt: u32, export: &server::NBDExport) -> Result<(), Error> {
+        let (volume_size, flags) = self.export_size_and_flags(export);
+        self.reply(opt, proto::NBD_REP_INFO, &codec::encode_info_export(volume_size, flags))?;
         log::debug!("\t-->Export Data Sent:");
         log::debug!("\t-->\t-->NBD_INFO_EXPORT: \t{:?}", proto::NBD_INFO_EXPORT as u16);
-        log::debug!("\t-->\t-->Volume Size: \t{:?}", volume_size as u32);
+        log::debug!("\t-->\t-->Volume Size: \t{:?}", volume_size);
         log::debug!("\t-->\t-->Transmission Flags: \t{:?}", flags as u16);
+        Ok(())
     }
 
-    fn reply_opt(&self, opt: u32, reply_type: u32, len: u32) {
-        let data_permitted = vec![1, 6, 7, 9, 10].contains(&opt); // OPTION CODES THAT IS ALLOWED TO CARRY DATA
-        self.reply(opt, reply_type, 4 + len);
-        {
-            let socket = Rc::clone(&self.socket);
-            util::write_u32(len, &mut socket.borrow_mut());
+    fn export_size_and_flags(&self, export: &server::NBDExport) -> (u64, u16) {
+        let mut flags: u16 = proto::NBD_FLAG_HAS_FLAGS | proto::NBD_FLAG_SEND_FLUSH | proto::NBD_FLAG_SEND_FUA | proto::NBD_FLAG_SEND_RESIZE | proto::NBD_FLAG_SEND_CACHE | proto::NBD_FLAG_SEND_WRITE_ZEROES;
+        // connections to an export share its driver and in-flight requests, a FLUSH on any of
+        // them covers the writes completed on all of them
+        flags |= proto::NBD_FLAG_CAN_MULTI_CONN;
+        if !export.is_writable() {
+            flags |= proto::NBD_FLAG_READ_ONLY;
+        }
+        // reads are only split into chunks with structured replies
+        if self.structured_reply.get() {
+            flags |= proto::NBD_FLAG_SEND_DF;
+        }
+        if export.trim {
+            flags |= proto::NBD_FLAG_SEND_TRIM;
+        }
+        if export.rotational {
+            flags |= proto::NBD_FLAG_ROTATIONAL;
+        }
+        (export.volume_size(), flags)
+    }
+
+    // Whether the option has to wait until the connection is upgraded to TLS
+    fn tls_pending(&self, option: u32) -> bool {
+        let required = match &self.tls_config {
+            Some(tls_config) => tls_config.required,
+            None => false,
+        };
+        required
+            && !self.socket.borrow().is_tls()
+            && option != proto::NBD_OPT_STARTTLS
+            && option != proto::NBD_OPT_ABORT
+    }
+
+    // Exports are only served to authenticated clients once a CA is configured
+    fn export_access_denied(&self) -> bool {
+        match &self.tls_config {
+            Some(tls_config) => tls_config.client_auth && !self.socket.borrow().is_tls(),
+            None => false,
+        }
+    }
+
+    fn handle_opt_starttls(&self, data: &[u8]) -> Result<(), Error> {
+        log::debug!("handle_opt_starttls");
+        if !data.is_empty() {
+            return self.reply(proto::NBD_OPT_STARTTLS, proto::NBD_REP_ERR_INVALID, &[])
+        }
+        let tls_config = match &self.tls_config {
+            Some(tls_config) => Arc::clone(tls_config),
+            None => return self.reply(proto::NBD_OPT_STARTTLS, proto::NBD_REP_ERR_UNSUP, &[]),
+        };
+        if self.socket.borrow().is_tls() {
+            return self.reply(proto::NBD_OPT_STARTTLS, proto::NBD_REP_ERR_INVALID, &[])
         }
-        log::debug!(" -> Option: {:?}, Option length: {:?}, Data permitted: {:?}", opt, len, data_permitted);
-
-        if len > 0 {
-            let mut data = vec![0; len as usize];
-            {
-                let socket = Rc::clone(&self.socket);
-                socket.borrow_mut() // Docs says server should not reject the data even if not needed
-                    .read_exact(&mut data)
-                    .expect("Error on reading Option Data!");
+
+        self.reply(proto::NBD_OPT_STARTTLS, proto::NBD_REP_ACK, &[])?;
+        let socket = self.socket.borrow().socket().try_clone()?;
+        match tls_config.accept(socket) {
+            Ok(stream) => {
+                log::info!("TLS established");
+                *self.socket.borrow_mut() = stream;
+                // Negotiation state from before the upgrade must be forgotten
+                self.structured_reply.set(false);
+                self.extended_headers.set(false);
+                self.reset_meta_contexts();
             }
-            log::trace!("\t\\-> Data: {:?}", data);
-            if data_permitted {
-                let socket = Rc::clone(&self.socket);
-                write!(&data, &mut socket.borrow_mut());
-                log::trace!("Data sent for option {:?}", opt);
+            Err(e) => {
+                log::warn!("TLS handshake failed: {}", e);
+                let _ = self.socket.borrow().shutdown(Shutdown::Both);
             }
         }
+        Ok(())
     }
 
-    fn handle_opt_abort(&self) {
-        /*
-        let len = util::read_u32(clone_stream!(socket));
-        self.reply_opt(
-            clone_stream!(socket),
-            proto::NBD_OPT_ABORT,
-            proto::NBD_REP_ERR_UNSUP,
-            len,
-        );
-        */
-    }
+    fn handle_opt_export_name(&self, data: &[u8]) -> Result<(), Error> {
+        log::debug!("handle_opt_export_name");
+        // the name takes up the whole option, there is no length prefix
+        let name = OptionData::new(data).string(data.len() as u32)?;
+        let name = if name.is_empty() { String::from("default") } else { name };
+        log::trace!("namelen:{},name:{}", data.len(), name);
 
-    fn handle_opt_info_go(&self, opt: u32) {
-        log::debug!("handle_opt_info_go");
-        let socket = Rc::clone(&self.socket);
-        let mut m_socket = socket.borrow_mut();
-        let _len = util::read_u32(&m_socket);
-        let namelen = util::read_u32(&m_socket);
-        // if namelen > len - 6 { return NBD_EINVAL }
-        let name = match namelen {
-            0 => "default".to_string(),
-            _ => util::read_string(namelen as usize, &mut m_socket),
+        if self.export_access_denied() {
+            log::warn!("Client is not authenticated for export: {}", &name);
+            self.close();
+            return Ok(())
+        }
+
+        // There is no way to report an error for this option, the spec requires the server to
+        // terminate the session instead.
+        let export_ref = match self.find_export(&name) {
+            Ok(export_ref) => export_ref,
+            Err(_) => {
+                self.close();
+                return Ok(())
+            }
+        };
+        let open_export = match server::NBDExport::open(&export_ref) {
+            Ok(open_export) => open_export,
+            Err(e) => {
+                log::error!("Couldn't open export {}: {}", &name, e);
+                self.close();
+                return Ok(())
+            }
         };
-        let info_req_count = util::read_u16(&m_socket);
-        let mut info_reqs = Vec::new();
-        for _ in 0..info_req_count {
-            info_reqs.push(util::read_u16(&m_socket));
+
+        let (volume_size, flags) = self.export_size_and_flags(&export_ref.read().unwrap());
+        codec::write_export_name_reply(&mut *self.socket.borrow_mut(), volume_size, flags, self.flags[1])?;
+        log::debug!("\t-->Export Data Sent:");
+        log::debug!("\t-->\t-->Volume Size: \t{:?}", volume_size);
+        log::debug!("\t-->\t-->Transmission Flags: \t{:?}", flags);
+        // No ACK follows, the client moves straight into transmission
+        self.phase.replace(Phase::Transmission(open_export));
+        Ok(())
+    }
+
+    fn handle_opt_list(&self, data: &[u8]) -> Result<(), Error> {
+        log::debug!("handle_opt_list");
+        if !data.is_empty() {
+            // NBD_OPT_LIST doesn't carry data
+            return self.reply(proto::NBD_OPT_LIST, proto::NBD_REP_ERR_INVALID, &[])
         }
-        drop(m_socket);
-        drop(socket);
-        log::trace!("len:{},namelen:{},name:{},info_req_count:{}", _len, namelen, name, info_req_count);
-        log::trace!("\t-->Info Requests: {:?}", info_reqs);
 
-        self.select_export(name.clone().to_lowercase());
-        if self.selected_export.borrow().is_none() {
-            log::warn!("Unknown export: {}", &name.to_lowercase());
-            let err_msg = String::from(format!("Unknown export: {}", &name.to_lowercase()));
-            let err_as_bytes = err_msg.as_bytes();
-            self.reply(
-                opt,
-                proto::NBD_REP_ERR_UNKNOWN,
-                err_as_bytes.len() as u32
-            );
-            let socket = Rc::clone(&self.socket);
-            write!(err_as_bytes, &mut socket.borrow_mut());
-            return
+        for export_ref in &*self.export_refs.read().unwrap() {
+            let export = export_ref.read().unwrap();
+            if !export.acl.permits(&self.peer) {
+                // exports the client can't open aren't advertised either
+                continue;
+            }
+            let description = export.description.clone().unwrap_or_default();
+            self.reply(proto::NBD_OPT_LIST, proto::NBD_REP_SERVER, &codec::encode_server(&export.name, &description))?;
+            log::trace!("\t-->Listed export: {}", export.name);
         }
+        self.reply(proto::NBD_OPT_LIST, proto::NBD_REP_ACK, &[])
+    }
+
+    // NBD_OPT_INFO only describes the export, NBD_OPT_GO also binds the session to it
+    fn handle_opt_info_go(&self, opt: u32, data: &[u8]) -> Result<(), Error> {
+        log::debug!("handle_opt_info_go");
+        let InfoRequest { name, info_reqs } = match InfoRequest::decode(data) {
+            Ok(request) => request,
+            Err(e) => {
+                log::warn!("Invalid option data: {}", e);
+                return self.reply_error(opt, proto::NBD_REP_ERR_INVALID, &e.to_string())
+            }
+        };
+        let name = if name.is_empty() { String::from("default") } else { name };
+        log::trace!("len:{},name:{}", data.len(), name);
+        log::trace!("\t-->Info Requests: {:?}", info_reqs);
 
-        if info_reqs.is_empty() { //The client MAY list one or more items of specific information it is seeking in the list of information requests, or it MAY specify an empty list.
-            info_reqs.push(3_u16);
+        if self.export_access_denied() {
+            log::warn!("Client is not authenticated for export: {}", &name);
+            return self.reply(opt, proto::NBD_REP_ERR_TLS_REQD, &[])
         }
 
-        for req in &info_reqs {
-            match *req {
-                proto::NBD_INFO_EXPORT => {// 0
-                    self.reply_info_export(opt, name.clone().to_lowercase());
-                }
-                proto::NBD_INFO_NAME => {// 1
-                    self.reply(opt, proto::NBD_REP_INFO, 0);
-
-                    let socket = Rc::clone(&self.socket);
-                    let m_socket = &*socket.borrow();
-                    let mut buf = BufWriter::new(m_socket);
-                    buf.write(&proto::NBD_INFO_NAME.to_be_bytes()).unwrap();
-                    buf.write(&name.as_bytes()).unwrap();
-                    buf.flush().unwrap();
-                }
-                proto::NBD_INFO_DESCRIPTION => {// 2
-                    let name_as_bytes = name.as_bytes();
-                    let length_of_name = name_as_bytes.len();
-                    self.reply(
-                        opt,
-                        proto::NBD_REP_INFO,
-                        2 + length_of_name as u32
-                    );
-                    let socket = Rc::clone(&self.socket);
-                    let m_socket = &*socket.borrow();
-                    let mut buf = BufWriter::new(m_socket);
-                    buf.write(&proto::NBD_INFO_DESCRIPTION.to_be_bytes()).unwrap();
-                    buf.write(&name_as_bytes).unwrap();
-                    buf.flush().unwrap();
+        let export_ref = match self.find_export(&name) {
+            Ok(export_ref) => export_ref,
+            Err(reply_type) => {
+                let err_msg = match reply_type {
+                    proto::NBD_REP_ERR_POLICY => format!("Access denied to export: {}", &name),
+                    _ => format!("Unknown export: {}", &name),
+                };
+                return self.reply_error(opt, reply_type, &err_msg)
+            }
+        };
+        // opened before describing it, so GO can still fail
+        let open_export = match opt {
+            proto::NBD_OPT_GO => match server::NBDExport::open(&export_ref) {
+                Ok(open_export) => Some(open_export),
+                Err(e) => {
+                    log::error!("Couldn't open export {}: {}", &name, e);
+                    return self.reply_error(opt, proto::NBD_REP_ERR_UNKNOWN, &format!("Couldn't open export: {}", &name))
                 }
-                proto::NBD_INFO_BLOCK_SIZE => {// 3
-                    self.reply(opt, proto::NBD_REP_INFO, 14);
-                    {
-                        let socket = Rc::clone(&self.socket);
-                        let m_socket = &*socket.borrow();
-                        let mut buf = BufWriter::new(m_socket);
-                        buf.write(&proto::NBD_INFO_BLOCK_SIZE.to_be_bytes()).unwrap();
-                        buf.write(&(512 as u32).to_be_bytes()).unwrap();
-                        buf.write(&(4 * 1024 as u32).to_be_bytes()).unwrap();
-                        buf.write(&(32 * 1024 * 1024 as u32).to_be_bytes()).unwrap();
-                        buf.flush().unwrap();
+            },
+            _ => None,
+        };
+
+        {
+            let export = export_ref.read().unwrap();
+            for req in &info_reqs {
+                match *req {
+                    proto::NBD_INFO_NAME => {// 1
+                        self.reply(opt, proto::NBD_REP_INFO, &codec::encode_info_string(proto::NBD_INFO_NAME, &name))?;
+                    }
+                    proto::NBD_INFO_DESCRIPTION => {// 2
+                        // exports without a description leave it out
+                        if let Some(description) = &export.description {
+                            self.reply(opt, proto::NBD_REP_INFO, &codec::encode_info_string(proto::NBD_INFO_DESCRIPTION, description))?;
+                        }
+                    }
+                    // sent either way below
+                    proto::NBD_INFO_EXPORT | proto::NBD_INFO_BLOCK_SIZE => (),// 0, 3
+                    r => {
+                        // unknown information requests are ignored
+                        log::warn!("Invalid Info Request: {:?}", r);
                     }
-                    log::debug!("\t-->Sent block size info");
-                    self.reply_info_export(opt, name.clone().to_lowercase());
-                }
-                r @ _ => {
-                    log::warn!("Invalid Info Request: {:?}", r);
-                    self.reply_opt(
-                        proto::NBD_REP_INFO,
-                        proto::NBD_EINVAL as u32,
-                        0
-                    )
                 }
             }
+            // the block sizes are sent unrequested too, but only enforced once requested
+            let BlockSize { min, preferred, max } = export.block_size;
+            self.reply(opt, proto::NBD_REP_INFO, &codec::encode_info_block_size(min, preferred, max))?;
+            log::debug!("\t-->Sent block size info");
+            self.reply_info_export(opt, &export)?;
         }
+        self.reply(opt, proto::NBD_REP_ACK, &[])?;
 
-        self.reply(opt, proto::NBD_REP_ACK, 0);
-        /*
-        if (opt == proto::NBD_OPT_GO) & (self.selected_export.is_none()) {
-            let session = self.session.take().unwrap();
-            self.session = Some(NBDSession::new(
-                clone_stream!(self.socket),
-                session.flags,
-                session.structured_reply,
-                selected_export.1.driver_type.clone(),
-                String::from(selected_export.0),
-                session.metadata_context_id,
-                selected_export.1.conn_str.clone()
-            ));
+        if let Some(open_export) = open_export {
+            if *self.meta_context_export.borrow() != name {
+                // contexts only apply to the export they were negotiated for
+                self.reset_meta_contexts();
+            }
+            self.block_size_requested.set(info_reqs.contains(&proto::NBD_INFO_BLOCK_SIZE));
+            self.phase.replace(Phase::Transmission(open_export));
         }
-        */
+        Ok(())
     }
 
 
-    fn handle_opt_set_meta_context(&self) {
-        log::debug!("handle_opt_set_meta_context");
-        let socket = Rc::clone(&self.socket);
-        let mut m_socket = socket.borrow_mut();
-        let total_length = util::read_u32(&m_socket);
-        let export_name_length = util::read_u32(&m_socket);
-        let export_name = match export_name_length {
-            0 => "default".to_string(),
-            _ => util::read_string(export_name_length as usize, &mut m_socket),
+    fn handle_opt_meta_context(&self, opt: u32, data: &[u8]) -> Result<(), Error> {
+        log::debug!("handle_opt_meta_context");
+        let MetaContextRequest { name: export_name, queries } = match MetaContextRequest::decode(data) {
+            Ok(request) => request,
+            Err(e) => {
+                log::warn!("Invalid option data: {}", e);
+                return self.reply_error(opt, proto::NBD_REP_ERR_INVALID, &e.to_string())
+            }
         };
-        let number_of_queries = util::read_u32(&m_socket);
-        drop(m_socket);
-        drop(socket);
-        log::trace!("\t-->total_length: {}, export_name_length: {}, export_name: {}, number_of_queries: {}", total_length, export_name_length, export_name, number_of_queries);
-        if number_of_queries > 0 {
-            for i in 0..number_of_queries {
-                let socket = Rc::clone(&self.socket);
-                let mut m_socket = socket.borrow_mut();
-                let query_length = util::read_u32(&m_socket);
-                let query = util::read_string(query_length as usize, &mut m_socket);
-                drop(m_socket);
-                drop(socket);
-                log::trace!("\t-->\t-->iter: {}, query: {}", i + 1, query);
-                self.reply(
-                    proto::NBD_OPT_SET_META_CONTEXT,
-                    proto::NBD_REP_META_CONTEXT,
-                    4 + query.len() as u32
-                );
-                let nbd_metadata_context_id = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .subsec_nanos();
-                self.metadata_context_id.set(nbd_metadata_context_id);
-                let socket = Rc::clone(&self.socket);
-                let m_socket = &*socket.borrow();
-                let mut buf = BufWriter::new(m_socket);
-                buf.write(&nbd_metadata_context_id.to_be_bytes()).unwrap();
-                buf.write(&query.to_lowercase().as_bytes()).unwrap();
-                buf.flush().unwrap();
+        let export_name = if export_name.is_empty() { String::from("default") } else { export_name };
+        // export and context names are case sensitive
+        log::trace!("\t-->total_length: {}, export_name: {}, queries: {:?}", data.len(), export_name, queries);
+
+        let listing = opt == proto::NBD_OPT_LIST_META_CONTEXT;
+        if !listing && !self.structured_reply.get() {
+            log::warn!("Metadata contexts requested before structured replies");
+            return self.reply(opt, proto::NBD_REP_ERR_INVALID, &[])
+        }
+        if let Err(reply_type) = self.find_export(&export_name) {
+            return self.reply(opt, reply_type, &[])
+        }
+
+        let mut contexts: Vec<(u32, &str)> = Vec::new();
+        if listing && queries.is_empty() {
+            contexts = meta_context::all_contexts();
+        }
+        for query in &queries {
+            for context in meta_context::match_query(query, listing) {
+                if !contexts.contains(&context) {
+                    contexts.push(context);
+                }
             }
         }
-        self.reply(proto::NBD_OPT_SET_META_CONTEXT, proto::NBD_REP_ACK, 0);
+        if !listing {
+            *self.meta_contexts.borrow_mut() = contexts.iter().map(|(id, _)| *id).collect();
+            *self.meta_context_export.borrow_mut() = export_name;
+        }
+
+        for (id, name) in contexts {
+            log::trace!("\t-->meta context: {} -> {}", id, name);
+            self.reply(opt, proto::NBD_REP_META_CONTEXT, &codec::encode_meta_context(id, name))?;
+        }
+        self.reply(opt, proto::NBD_REP_ACK, &[])
     }
 
-    fn select_export(&self, export_name: String) {
-        for export in &*self.export_refs.read().unwrap() {
-            if *export.read().unwrap().name == export_name {
-                self.selected_export.replace_with(|_| Some(Arc::clone(&export)));
-                break;
+    // Looks up an export the client is allowed to open, or the NBD_REP_ERR_* to refuse it with
+    fn find_export(&self, export_name: &str) -> Result<Arc<RwLock<server::NBDExport>>, u32> {
+        let exports = self.export_refs.read().unwrap();
+        let export = match exports.iter().find(|export| export.read().unwrap().name == export_name) {
+            Some(export) => export,
+            None => {
+                log::warn!("Unknown export: {}", export_name);
+                return Err(proto::NBD_REP_ERR_UNKNOWN)
             }
+        };
+        if !export.read().unwrap().acl.permits(&self.peer) {
+            log::warn!("Access denied to export {} for {:?}", export_name, self.peer);
+            return Err(proto::NBD_REP_ERR_POLICY)
         }
+        Ok(Arc::clone(export))
     }
 }
 
@@ -0,0 +1,202 @@
+use std::{
+    io::{Read, Write, Result, Error, ErrorKind},
+    net::{TcpStream, Shutdown},
+    os::unix::net::UnixStream,
+    sync::{Arc, Mutex},
+};
+
+use rustls::{ServerConnection, StreamOwned};
+
+// Socket a client connected through, depending on the listener that accepted it
+pub enum NBDSocket {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl NBDSocket {
+    pub fn try_clone(&self) -> Result<NBDSocket> {
+        match self {
+            NBDSocket::Tcp(socket) => Ok(NBDSocket::Tcp(socket.try_clone()?)),
+            NBDSocket::Unix(socket) => Ok(NBDSocket::Unix(socket.try_clone()?)),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> Result<()> {
+        match self {
+            NBDSocket::Tcp(socket) => socket.shutdown(how),
+            NBDSocket::Unix(socket) => socket.shutdown(how),
+        }
+    }
+}
+
+impl Read for NBDSocket {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self {
+            NBDSocket::Tcp(socket) => socket.read(buf),
+            NBDSocket::Unix(socket) => socket.read(buf),
+        }
+    }
+}
+
+impl Write for NBDSocket {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match self {
+            NBDSocket::Tcp(socket) => socket.write(buf),
+            NBDSocket::Unix(socket) => socket.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            NBDSocket::Tcp(socket) => socket.flush(),
+            NBDSocket::Unix(socket) => socket.flush(),
+        }
+    }
+}
+
+// Client connection of a session, can be upgraded to TLS during option haggling (NBD_OPT_STARTTLS)
+pub enum NBDStream {
+    Plain(NBDSocket),
+    Tls(Box<StreamOwned<ServerConnection, NBDSocket>>),
+}
+
+impl NBDStream {
+    pub fn socket(&self) -> &NBDSocket {
+        match self {
+            NBDStream::Plain(socket) => socket,
+            NBDStream::Tls(stream) => &stream.sock,
+        }
+    }
+
+    pub fn is_tls(&self) -> bool {
+        matches!(self, NBDStream::Tls(_))
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> Result<()> {
+        self.socket().shutdown(how)
+    }
+
+    // Splits the stream for transmission, so replies can be written while the next request is read.
+    // With TLS both halves share the connection state, but only the reader waits on the socket.
+    pub fn split(self) -> Result<(NBDReader, NBDWriter)> {
+        match self {
+            NBDStream::Plain(socket) => Ok((NBDReader::Plain(socket.try_clone()?), NBDWriter::Plain(socket))),
+            NBDStream::Tls(stream) => {
+                let StreamOwned { conn, sock } = *stream;
+                let conn = Arc::new(Mutex::new(conn));
+                Ok((NBDReader::Tls(Arc::clone(&conn), sock.try_clone()?, Vec::new()), NBDWriter::Tls(conn, sock)))
+            }
+        }
+    }
+}
+
+pub enum NBDReader {
+    Plain(NBDSocket),
+    // records received from the socket, but not yet taken by the connection
+    Tls(Arc<Mutex<ServerConnection>>, NBDSocket, Vec<u8>),
+}
+
+pub enum NBDWriter {
+    Plain(NBDSocket),
+    Tls(Arc<Mutex<ServerConnection>>, NBDSocket),
+}
+
+impl NBDWriter {
+    pub fn shutdown(&self, how: Shutdown) -> Result<()> {
+        match self {
+            NBDWriter::Plain(socket) => socket.shutdown(how),
+            NBDWriter::Tls(_, socket) => socket.shutdown(how),
+        }
+    }
+}
+
+impl Read for NBDReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self {
+            NBDReader::Plain(socket) => socket.read(buf),
+            NBDReader::Tls(conn, socket, records) => loop {
+                {
+                    let mut conn = conn.lock().unwrap();
+                    // hand over the received records, as far as the plaintext buffer allows
+                    while !records.is_empty() {
+                        let mut pending = &records[..];
+                        let consumed = match conn.read_tls(&mut pending) {
+                            Ok(_) => records.len() - pending.len(),
+                            Err(_) => 0,
+                        };
+                        if consumed == 0 {
+                            break
+                        }
+                        records.drain(..consumed);
+                        conn.process_new_packets().map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+                    }
+                    // i.e. key updates, which have to be answered
+                    while conn.wants_write() {
+                        conn.write_tls(socket)?;
+                    }
+                    match conn.reader().read(buf) {
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+                        result => return result,
+                    }
+                }
+                // wait for more records without holding the connection, so replies can go out meanwhile
+                let mut received = [0_u8; 16 * 1024];
+                let len = socket.read(&mut received)?;
+                if len == 0 {
+                    // lets the reader report the EOF
+                    conn.lock().unwrap().read_tls(&mut &received[..0])?;
+                }
+                records.extend_from_slice(&received[..len]);
+            },
+        }
+    }
+}
+
+impl Write for NBDWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match self {
+            NBDWriter::Plain(socket) => socket.write(buf),
+            NBDWriter::Tls(conn, socket) => {
+                // the connection only buffers so much plaintext, `write_all` comes back for the rest
+                let mut conn = conn.lock().unwrap();
+                let written = conn.writer().write(buf)?;
+                while conn.wants_write() {
+                    conn.write_tls(socket)?;
+                }
+                Ok(written)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            NBDWriter::Plain(socket) => socket.flush(),
+            NBDWriter::Tls(_, socket) => socket.flush(),
+        }
+    }
+}
+
+impl Read for NBDStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self {
+            NBDStream::Plain(socket) => socket.read(buf),
+            NBDStream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for NBDStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match self {
+            NBDStream::Plain(socket) => socket.write(buf),
+            NBDStream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            NBDStream::Plain(socket) => socket.flush(),
+            NBDStream::Tls(stream) => stream.flush(),
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{self, BufReader, ErrorKind},
+    sync::Arc,
+};
+
+use rustls::{
+    server::AllowAnyAuthenticatedClient,
+    Certificate, PrivateKey, RootCertStore, ServerConfig, ServerConnection, StreamOwned,
+};
+use rustls_pemfile::Item;
+
+use crate::nbd::{NBDSocket, NBDStream};
+
+pub struct TlsConfig {
+    // Answer every option except STARTTLS/ABORT with NBD_REP_ERR_TLS_REQD until the upgrade
+    pub required: bool,
+    // Clients have to present a certificate signed by the configured CA to use exports
+    pub client_auth: bool,
+    server_config: Arc<ServerConfig>,
+}
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>, Box<dyn Error>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut reader)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(format!("No certificates found in {}", path).into());
+    }
+    Ok(certs)
+}
+
+fn load_private_key(path: &str) -> Result<PrivateKey, Box<dyn Error>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    for item in rustls_pemfile::read_all(&mut reader)? {
+        match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => continue,
+        }
+    }
+    Err(format!("No private key found in {}", path).into())
+}
+
+impl TlsConfig {
+    pub fn new(cert_path: &str, key_path: &str, ca_path: Option<&str>, required: bool) -> Result<TlsConfig, Box<dyn Error>> {
+        let certs = load_certs(cert_path)?;
+        let key = load_private_key(key_path)?;
+        let builder = ServerConfig::builder().with_safe_defaults();
+
+        let server_config = match ca_path {
+            Some(ca_path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in load_certs(ca_path)? {
+                    roots.add(&cert)?;
+                }
+                builder
+                    .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+                    .with_single_cert(certs, key)?
+            },
+            None => builder
+                .with_no_client_auth()
+                .with_single_cert(certs, key)?,
+        };
+
+        log::info!("TLS enabled (required: {}, client certificates: {})", required, ca_path.is_some());
+        Ok(TlsConfig {
+            required,
+            client_auth: ca_path.is_some(),
+            server_config: Arc::new(server_config),
+        })
+    }
+
+    // Runs the server side of the TLS handshake on `socket`
+    pub fn accept(&self, socket: NBDSocket) -> Result<NBDStream, io::Error> {
+        let conn = ServerConnection::new(Arc::clone(&self.server_config))
+            .map_err(|e| io::Error::new(ErrorKind::Other, e))?;
+        let mut stream = StreamOwned::new(conn, socket);
+        while stream.conn.is_handshaking() {
+            stream.conn.complete_io(&mut stream.sock)?;
+        }
+        Ok(NBDStream::Tls(Box::new(stream)))
+    }
+}
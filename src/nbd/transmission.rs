@@ -0,0 +1,850 @@
+// Transmission phase of a session. Requests are read by the session thread and executed by a
+// bounded pool of workers, so a slow request doesn't hold back the ones queued behind it. NBD
+// handles allow replies in any order, they are sent by a single writer thread as they complete.
+
+use std::{
+    io::{Write, Error, ErrorKind},
+    net::Shutdown,
+    sync::{Arc, Mutex, Condvar, PoisonError, RwLock, mpsc::sync_channel, atomic::{AtomicU64, Ordering}},
+    thread,
+};
+
+use crate::{
+    block::{BlockStorage, Lease},
+    util::{self, Extent},
+    nbd::{proto, codec::{self, NBDRequest}, errno, meta_context, BlockSize, NBDReader, NBDWriter},
+};
+
+// requests of a connection that are executed at the same time
+const WORKER_THREADS: usize = 8;
+// requests read ahead of the workers, the kernel client keeps up to 128 of them in flight
+const QUEUE_DEPTH: usize = 128;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Access {
+    Read,
+    Write,
+    // waits for the writes before it, i.e. NBD_CMD_FLUSH
+    Flush,
+    // waits for everything before it, and holds back everything after it, i.e. NBD_CMD_RESIZE
+    Barrier,
+}
+
+impl Access {
+    fn of(req_type: u16) -> Access {
+        match req_type {
+            proto::NBD_CMD_READ | proto::NBD_CMD_CACHE | proto::NBD_CMD_BLOCK_STATUS => Access::Read,
+            proto::NBD_CMD_WRITE | proto::NBD_CMD_TRIM | proto::NBD_CMD_WRITE_ZEROES => Access::Write,
+            proto::NBD_CMD_FLUSH => Access::Flush,
+            _ => Access::Barrier,
+        }
+    }
+}
+
+fn modifies(req_type: u16) -> bool {
+    [proto::NBD_CMD_WRITE, proto::NBD_CMD_TRIM, proto::NBD_CMD_WRITE_ZEROES, proto::NBD_CMD_RESIZE].contains(&req_type)
+}
+
+#[derive(Copy, Clone, Debug)]
+struct InFlightRequest {
+    seq: u64,
+    offset: u64,
+    length: u64,
+    access: Access,
+}
+
+impl InFlightRequest {
+    // Whether `later` has to wait for this request to complete
+    fn conflicts_with(&self, later: &InFlightRequest) -> bool {
+        match (self.access, later.access) {
+            (Access::Barrier, _) | (_, Access::Barrier) => true,
+            (Access::Write, Access::Flush) => true,
+            (Access::Flush, _) | (_, Access::Flush) => false,
+            (Access::Read, Access::Read) => false,
+            _ => self.offset < later.offset.saturating_add(later.length)
+                && later.offset < self.offset.saturating_add(self.length),
+        }
+    }
+}
+
+// Refuses requests the driver can't be trusted with: reads and writes larger than the maximum
+// block size, ranges past the end of the export, unaligned ranges once the client agreed to the
+// minimum block size, empty block status queries and resizes to zero. Returns the errno and
+// message to reply with.
+fn check_request(request: &NBDRequest, volume_size: u64, block_size: &BlockSize, aligned: bool) -> Result<(), (u8, &'static str)> {
+    match request.req_type {
+        proto::NBD_CMD_READ | proto::NBD_CMD_WRITE => {
+            if request.length > block_size.max as u64 {
+                return Err((proto::NBD_EOVERFLOW, "Request is larger than the maximum block size"))
+            }
+        },
+        // the reply has to describe at least one extent
+        proto::NBD_CMD_BLOCK_STATUS if request.length == 0 => {
+            return Err((proto::NBD_EINVAL, "Block status of an empty range"))
+        },
+        proto::NBD_CMD_TRIM | proto::NBD_CMD_CACHE | proto::NBD_CMD_WRITE_ZEROES | proto::NBD_CMD_BLOCK_STATUS => (),
+        // the new size is carried in the offset field
+        proto::NBD_CMD_RESIZE => {
+            if request.offset == 0 {
+                return Err((proto::NBD_EINVAL, "Volume size can not be zero"))
+            }
+            if aligned && request.offset % block_size.min as u64 != 0 {
+                return Err((proto::NBD_EINVAL, "Volume size is not aligned to the minimum block size"))
+            }
+            return Ok(())
+        },
+        _ => return Ok(()),
+    }
+    let end = request.offset.checked_add(request.length);
+    if end.map_or(true, |end| end > volume_size) {
+        return match request.req_type {
+            proto::NBD_CMD_WRITE | proto::NBD_CMD_WRITE_ZEROES => Err((proto::NBD_ENOSPC, "Request ends past the end of the export")),
+            _ => Err((proto::NBD_EINVAL, "Request ends past the end of the export")),
+        }
+    }
+    let min = block_size.min as u64;
+    // the export may end with a partial block
+    if aligned && (request.offset % min != 0 || (request.length % min != 0 && end != Some(volume_size))) {
+        return Err((proto::NBD_EINVAL, "Request is not aligned to the minimum block size"))
+    }
+    Ok(())
+}
+
+// Requests that were read but haven't completed yet, in arrival order. Overlapping requests
+// still run in the order they were sent, as long as one of them modifies the range. There is
+// one per export, shared by all of its connections, so a FLUSH also waits for the writes that
+// other connections sent before it (NBD_FLAG_CAN_MULTI_CONN).
+pub struct InFlight {
+    requests: Mutex<Vec<InFlightRequest>>,
+    completed: Condvar,
+    last_seq: AtomicU64,
+}
+
+impl InFlight {
+    pub fn new() -> InFlight {
+        InFlight {
+            requests: Mutex::new(Vec::new()),
+            completed: Condvar::new(),
+            last_seq: AtomicU64::new(0),
+        }
+    }
+
+    // Returns the turn the request has to wait for, it completes when dropped
+    fn register(self: &Arc<Self>, offset: u64, length: u64, access: Access) -> Turn {
+        let mut requests = self.requests.lock().unwrap();
+        // taken under the lock, so `requests` stays in arrival order
+        let seq = self.last_seq.fetch_add(1, Ordering::SeqCst) + 1;
+        requests.push(InFlightRequest { seq, offset, length, access });
+        Turn { in_flight: Arc::clone(self), seq }
+    }
+
+    fn wait_turn(&self, seq: u64) {
+        let mut requests = self.requests.lock().unwrap();
+        loop {
+            let current = *requests.iter().find(|r| r.seq == seq).unwrap();
+            let blocked = requests.iter()
+                .take_while(|r| r.seq < seq)
+                .any(|r| r.conflicts_with(&current));
+            if !blocked {
+                return
+            }
+            requests = self.completed.wait(requests).unwrap();
+        }
+    }
+
+    fn complete(&self, seq: u64) {
+        // also called while unwinding, after a request panicked
+        self.requests.lock().unwrap_or_else(PoisonError::into_inner).retain(|r| r.seq != seq);
+        self.completed.notify_all();
+    }
+}
+
+// A registered request. It completes when dropped, even if the request panicked or was never
+// executed, otherwise the requests of the export queued behind it would wait forever.
+struct Turn {
+    in_flight: Arc<InFlight>,
+    seq: u64,
+}
+
+impl Turn {
+    fn wait(&self) {
+        self.in_flight.wait_turn(self.seq);
+    }
+}
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        self.in_flight.complete(self.seq);
+    }
+}
+
+// What was negotiated for the transmission, besides the export's driver
+pub struct TransmissionOptions {
+    pub read_only: bool,
+    // writes are refused once the lease on the volume is lost
+    pub lease: Option<Arc<Lease>>,
+    pub structured_reply: bool,
+    pub extended_headers: bool,
+    pub meta_contexts: Vec<u32>,
+    pub block_size: BlockSize,
+    // whether the client asked for NBD_INFO_BLOCK_SIZE, and so agreed to the minimum block size
+    pub aligned: bool,
+}
+
+// Fields are the same as `TransmissionOptions`
+pub struct Transmission {
+    driver: Arc<RwLock<Box<dyn BlockStorage>>>,
+    in_flight: Arc<InFlight>,
+    read_only: bool,
+    lease: Option<Arc<Lease>>,
+    structured_reply: bool,
+    extended_headers: bool,
+    meta_contexts: Vec<u32>,
+    block_size: BlockSize,
+    aligned: bool,
+}
+
+impl Transmission {
+    pub fn new(driver: Arc<RwLock<Box<dyn BlockStorage>>>, in_flight: Arc<InFlight>, options: TransmissionOptions) -> Transmission {
+        let TransmissionOptions { read_only, lease, structured_reply, extended_headers, meta_contexts, block_size, aligned } = options;
+        Transmission {
+            driver,
+            read_only,
+            lease,
+            structured_reply,
+            extended_headers,
+            meta_contexts,
+            in_flight,
+            block_size,
+            aligned,
+        }
+    }
+
+    // Serves requests until the client disconnects, then waits for the in-flight ones to be replied
+    pub fn serve(self: Arc<Self>, mut reader: NBDReader, writer: NBDWriter) -> Result<(), Error> {
+        let name = thread::current().name().unwrap_or("session").to_string();
+
+        let (reply_sender, reply_receiver) = sync_channel::<Vec<u8>>(QUEUE_DEPTH);
+        let writer_thread = thread::Builder::new()
+            .name(format!("{}-writer", name))
+            .spawn(move || {
+                let mut writer = writer;
+                for reply in reply_receiver {
+                    if let Err(e) = writer.write_all(&reply).and_then(|_| writer.flush()) {
+                        log::error!("Couldn't send reply: {}", e);
+                        // unblocks the reader as well
+                        let _ = writer.shutdown(Shutdown::Both);
+                        break;
+                    }
+                }
+            })?;
+
+        let (request_sender, request_receiver) = sync_channel::<(Turn, NBDRequest)>(QUEUE_DEPTH);
+        let request_receiver = Arc::new(Mutex::new(request_receiver));
+        let mut workers = Vec::new();
+        for i in 0..WORKER_THREADS {
+            let transmission = Arc::clone(&self);
+            let request_receiver = Arc::clone(&request_receiver);
+            let reply_sender = reply_sender.clone();
+            let worker = thread::Builder::new()
+                .name(format!("{}-worker-{}", name, i))
+                .spawn(move || loop {
+                    let received = request_receiver.lock().unwrap().recv();
+                    let (turn, request) = match received {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+                    turn.wait();
+                    let reply = transmission.execute(&request);
+                    drop(turn);
+                    // keep draining after the writer is gone, other connections of the export
+                    // may be waiting for these requests to complete
+                    let _ = reply_sender.send(reply);
+                })?;
+            workers.push(worker);
+        }
+        // only the workers receive, so sending fails once they are all gone
+        drop(request_receiver);
+
+        loop {
+            let mut request = match NBDRequest::read(&mut reader, self.extended_headers) {
+                Ok(request) => request,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                    log::info!("Terminating connection.");
+                    break;
+                },
+                Err(e) => {
+                    log::error!("{}", e);
+                    break;
+                }
+            };
+            log::trace!("\t-->flags:{}, type: {}, handle: {}, offset: {}, datalen: {}", request.flags, request.req_type, request.handle, request.offset, request.length);
+
+            if request.req_type == proto::NBD_CMD_DISC {
+                log::debug!("NBD_CMD_DISC");
+                break;
+            }
+            let refusal = if modifies(request.req_type) && self.read_only {
+                Some((proto::NBD_EPERM, "Export is read-only"))
+            } else if let Some(refusal) = self.lease_refusal(request.req_type) {
+                Some(refusal)
+            } else if request.has_payload() && request.length > self.block_size.max as u64 {
+                Some((proto::NBD_EOVERFLOW, "Request is larger than the maximum block size"))
+            } else {
+                None
+            };
+            // refused payloads are drained instead of buffered, to keep the connection usable
+            let payload_res = match refusal {
+                Some(_) if request.has_payload() => request.skip_payload(&mut reader),
+                None if request.has_payload() => request.read_payload(&mut reader),
+                _ => Ok(()),
+            };
+            if let Err(e) = payload_res {
+                log::error!("Couldn't read the payload of CMD {}: {}", request.req_type, e);
+                break;
+            }
+            if let Some((errno, message)) = refusal {
+                log::warn!("Refusing CMD {}: {}", request.req_type, message);
+                let mut reply = Vec::new();
+                self.reply_error(&mut reply, request.handle, request.offset, errno, message);
+                if reply_sender.send(reply).is_err() {
+                    break;
+                }
+                continue;
+            }
+
+            let turn = self.in_flight.register(request.offset, request.length, Access::of(request.req_type));
+            if request_sender.send((turn, request)).is_err() {
+                log::error!("No workers left to execute requests");
+                break;
+            }
+        }
+
+        // pending requests still get their replies
+        drop(request_sender);
+        for worker in workers {
+            if worker.join().is_err() {
+                log::error!("A worker panicked");
+            }
+        }
+        drop(reply_sender);
+        if writer_thread.join().is_err() {
+            log::error!("The reply writer panicked");
+        }
+        Ok(())
+    }
+
+    // Once the lease is lost, writes that weren't written back were dropped (see
+    // `Lease::fence_on_loss`), so flushes can't succeed anymore either
+    fn lease_refusal(&self, req_type: u16) -> Option<(u8, &'static str)> {
+        if self.lease.as_ref().map_or(true, |lease| lease.is_held()) {
+            return None
+        }
+        if modifies(req_type) {
+            return Some((proto::NBD_EPERM, "Lease on the volume is lost"))
+        }
+        if req_type == proto::NBD_CMD_FLUSH {
+            return Some((proto::NBD_EIO, "Lease on the volume is lost, unwritten data was dropped"))
+        }
+        None
+    }
+
+    // Runs a request against the driver, and returns the encoded reply
+    fn execute(&self, request: &NBDRequest) -> Vec<u8> {
+        let mut reply = Vec::new();
+        let NBDRequest { flags, req_type, handle, offset, length, .. } = *request;
+        let datalen = length as usize;
+        // checked once it is the request's turn, after any resize before it
+        let volume_size = self.driver.read().unwrap().get_volume_size();
+        if let Err((errno, message)) = check_request(request, volume_size, &self.block_size, self.aligned) {
+            log::warn!("Refusing CMD {} at offset {}, length {}: {}", req_type, offset, length, message);
+            self.reply_error(&mut reply, handle, offset, errno, message);
+            return reply
+        }
+        // the lease may have been lost while the request was queued
+        if let Some((errno, message)) = self.lease_refusal(req_type) {
+            log::warn!("Refusing CMD {}: {}", req_type, message);
+            self.reply_error(&mut reply, handle, offset, errno, message);
+            return reply
+        }
+        match req_type {
+            proto::NBD_CMD_READ => { // 0
+                log::debug!("NBD_CMD_READ");
+                // NBD_CMD_FLAG_DF: the client wants the whole range in a single chunk
+                if self.structured_reply && (flags & proto::NBD_CMD_FLAG_DF) == 0 {
+                    self.reply_read_chunks(&mut reply, handle, offset, datalen);
+                    return reply
+                }
+                let buffer_res = self.driver.read().unwrap().read(offset, datalen);
+                match buffer_res {
+                    Ok(buffer) => {
+                        log::trace!("NBD_CMD_READ ok!");
+                        if self.structured_reply {
+                            self.reply_data_chunk(&mut reply, proto::NBD_REPLY_FLAG_DONE, handle, offset, offset, &buffer);
+                        } else {
+                            self.simple_reply(&mut reply, 0_u32, handle);
+                            reply.extend_from_slice(&buffer);
+                        }
+                    },
+                    Err(e) => {
+                        log::warn!("NBD_CMD_READ failed: {}", e);
+                        self.reply_io_error(&mut reply, handle, offset, &e);
+                    }
+                }
+            }
+            proto::NBD_CMD_WRITE => { // 1
+                log::debug!("NBD_CMD_WRITE");
+                // writes still take the driver exclusively, sharded drivers rewrite whole shards, so
+                // two writes into the same shard would race; FUA persists after releasing it
+                let write_res = self.driver.write().unwrap().write(offset, datalen, &request.data);
+                match write_res.and_then(|_| self.persist_if_fua(flags, offset, datalen)) {
+                    Ok(_) => {
+                        log::trace!("NBD_CMD_WRITE ok!");
+                        self.reply_done(&mut reply, handle, offset);
+                    },
+                    Err(e) => {
+                        log::warn!("NBD_CMD_WRITE failed: {}", e);
+                        self.reply_io_error(&mut reply, handle, offset, &e);
+                    }
+                }
+            }
+            proto::NBD_CMD_FLUSH => { // 3
+                // offset and length are zero here, the whole volume is flushed
+                log::debug!("NBD_CMD_FLUSH");
+                let volume_size = self.driver.read().unwrap().get_volume_size() as usize;
+                match self.persist(0, volume_size) {
+                    Ok(_) => {
+                        log::trace!("flushed");
+                        self.reply_done(&mut reply, handle, offset);
+                    },
+                    Err(e) => {
+                        log::warn!("NBD_CMD_FLUSH failed: {}", e);
+                        self.reply_io_error(&mut reply, handle, offset, &e);
+                    }
+                }
+            }
+            proto::NBD_CMD_TRIM => { // 4
+                log::debug!("NBD_CMD_TRIM");
+                let trim_res = match self.driver.write().unwrap().trim(offset, datalen) {
+                    // trims are only a hint, drivers that can't punch holes keep the data
+                    Err(e) if e.kind() == ErrorKind::Unsupported => Ok(()),
+                    trim_res => trim_res.map(|_| ()),
+                };
+                match trim_res.and_then(|_| self.persist_if_fua(flags, offset, datalen)) {
+                    Ok(_) => {
+                        log::trace!("trimmed");
+                        self.reply_done(&mut reply, handle, offset);
+                    },
+                    Err(e) => {
+                        log::warn!("NBD_CMD_TRIM failed: {}", e);
+                        self.reply_io_error(&mut reply, handle, offset, &e);
+                    }
+                }
+            }
+            proto::NBD_CMD_CACHE => { // 5
+                log::debug!("NBD_CMD_CACHE");
+                let prefetch_res = self.driver.read().unwrap().prefetch(offset, datalen);
+                match prefetch_res {
+                    Ok(_) => {
+                        log::trace!("NBD_CMD_CACHE ok!");
+                        self.reply_done(&mut reply, handle, offset);
+                    },
+                    Err(e) => {
+                        log::warn!("NBD_CMD_CACHE failed: {}", e);
+                        self.reply_io_error(&mut reply, handle, offset, &e);
+                    }
+                }
+            }
+            proto::NBD_CMD_WRITE_ZEROES => { // 6
+                log::debug!("NBD_CMD_WRITE_ZEROES");
+                let no_hole = (flags & proto::NBD_CMD_FLAG_NO_HOLE) != 0;
+                let zero_res = self.driver.write().unwrap().write_zeroes(offset, datalen, no_hole);
+                match zero_res.and_then(|_| self.persist_if_fua(flags, offset, datalen)) {
+                    Ok(_) => {
+                        log::trace!("NBD_CMD_WRITE_ZEROES ok!");
+                        self.reply_done(&mut reply, handle, offset);
+                    },
+                    Err(e) => {
+                        log::warn!("NBD_CMD_WRITE_ZEROES failed: {}", e);
+                        self.reply_io_error(&mut reply, handle, offset, &e);
+                    }
+                }
+            }
+            proto::NBD_CMD_BLOCK_STATUS => { // 7
+                log::debug!("NBD_CMD_BLOCK_STATUS");
+                self.reply_block_status(&mut reply, flags, handle, offset, datalen);
+            }
+            proto::NBD_CMD_RESIZE => { // 8
+                // the new size is carried in the offset field
+                log::debug!("NBD_CMD_RESIZE");
+                let resize_res = self.driver.write().unwrap().resize(offset);
+                match resize_res {
+                    Ok(_) => {
+                        log::trace!("NBD_CMD_RESIZE ok!");
+                        self.reply_done(&mut reply, handle, offset);
+                    },
+                    Err(e) => {
+                        log::warn!("NBD_CMD_RESIZE failed: {}", e);
+                        self.reply_io_error(&mut reply, handle, offset, &e);
+                    }
+                }
+            }
+            _ => {
+                log::warn!("Invalid/Unimplemented CMD: {:?}", req_type);
+                self.reply_error(&mut reply, handle, offset, proto::NBD_EINVAL, "Unknown command");
+            }
+        }
+        reply
+    }
+
+    // Returns once the range reached a durable propagation through every layer of the driver
+    fn persist(&self, offset: u64, length: usize) -> Result<(), Error> {
+        if length == 0 {
+            return Ok(())
+        }
+        // the writes it waits for are ordered by `InFlight`, so other requests keep running
+        let propagation = self.driver.read().unwrap().flush(offset, length)?;
+        if !propagation.is_durable() {
+            return Err(Error::new(ErrorKind::Other, format!("Flush is not durable ({:?})", propagation)));
+        }
+        Ok(())
+    }
+
+    fn persist_if_fua(&self, flags: u16, offset: u64, length: usize) -> Result<(), Error> {
+        if (flags & proto::NBD_CMD_FLAG_FUA) == 0 {
+            return Ok(())
+        }
+        self.persist(offset, length)
+    }
+
+    // Replies to a read with one chunk per extent, holes are sent as NBD_REPLY_TYPE_OFFSET_HOLE
+    // instead of reading and sending their zeroes.
+    fn reply_read_chunks(&self, reply: &mut Vec<u8>, handle: u64, request_offset: u64, length: usize) {
+        if length == 0 {
+            self.reply_done(reply, handle, request_offset);
+            return
+        }
+        // holes are only reported when the driver knows them without asking the storage
+        let mut extents = Vec::new();
+        let driver = self.driver.read().unwrap();
+        if driver.supports_fast_block_status() {
+            match driver.block_status(request_offset, length) {
+                Ok(found) => extents = found,
+                Err(e) => log::warn!("block status failed, reading as data: {}", e),
+            }
+        }
+        drop(driver);
+        let covered: u64 = extents.iter().map(|extent| extent.length).sum();
+        if covered < length as u64 {
+            util::push_extent(&mut extents, Extent::data(length as u64 - covered));
+        }
+
+        let end = request_offset + length as u64;
+        let mut chunk_offset = request_offset;
+        for extent in extents {
+            let chunk_len = extent.length.min(end - chunk_offset);
+            let flags = if chunk_offset + chunk_len == end { proto::NBD_REPLY_FLAG_DONE } else { 0 };
+            if extent.zero {
+                log::trace!("read hole: offset: {}, length: {}", chunk_offset, chunk_len);
+                self.structured_reply(reply, flags, proto::NBD_REPLY_TYPE_OFFSET_HOLE, handle, request_offset, 12);
+                reply.extend_from_slice(&chunk_offset.to_be_bytes());
+                reply.extend_from_slice(&(chunk_len as u32).to_be_bytes());
+            } else {
+                let buffer_res = self.driver.read().unwrap().read(chunk_offset, chunk_len as usize);
+                match buffer_res {
+                    Ok(buffer) => self.reply_data_chunk(reply, flags, handle, request_offset, chunk_offset, &buffer),
+                    Err(e) => {
+                        log::warn!("NBD_CMD_READ failed at {}: {}", chunk_offset, e);
+                        self.reply_error_offset(reply, handle, request_offset, chunk_offset, &e);
+                        return
+                    }
+                }
+            }
+            chunk_offset += chunk_len;
+            if chunk_offset == end {
+                break
+            }
+        }
+        log::trace!("NBD_CMD_READ ok!");
+    }
+
+    fn reply_block_status(&self, reply: &mut Vec<u8>, flags: u16, handle: u64, offset: u64, length: usize) {
+        let single_extent_only = (flags & proto::NBD_CMD_FLAG_REQ_ONE) != 0;
+        if !self.structured_reply || self.meta_contexts.is_empty() {
+            log::warn!("NBD_CMD_BLOCK_STATUS without an active metadata context");
+            self.reply_error(reply, handle, offset, proto::NBD_EINVAL, "No metadata context is negotiated");
+            return
+        }
+        let status_res = self.driver.read().unwrap().block_status(offset, length);
+        let mut extents = match status_res {
+            Ok(extents) => extents,
+            Err(e) => {
+                log::warn!("NBD_CMD_BLOCK_STATUS failed: {}", e);
+                self.reply_io_error(reply, handle, offset, &e);
+                return
+            }
+        };
+        if single_extent_only {
+            extents.truncate(1);
+        }
+        log::trace!("NBD_CMD_BLOCK_STATUS ok! {} extent(s)", extents.len());
+        // one chunk per active context, the last one ends the reply
+        for (i, context_id) in self.meta_contexts.iter().enumerate() {
+            let reply_flags = if i == self.meta_contexts.len() - 1 { proto::NBD_REPLY_FLAG_DONE } else { 0 };
+            let descriptors: Vec<(u64, u32)> = match *context_id {
+                meta_context::BASE_ALLOCATION => extents.iter().map(|extent| {
+                    let mut state: u32 = 0;
+                    if extent.hole {
+                        state |= proto::NBD_STATE_HOLE;
+                    }
+                    if extent.zero {
+                        state |= proto::NBD_STATE_ZERO;
+                    }
+                    (extent.length, state)
+                }).collect(),
+                _ => vec![(length as u64, 0)],
+            };
+            if self.extended_headers {
+                // 64-bit descriptors, prefixed with their count
+                self.structured_reply(
+                    reply,
+                    reply_flags,
+                    proto::NBD_REPLY_TYPE_BLOCK_STATUS_EXT,
+                    handle,
+                    offset,
+                    8 + 16 * descriptors.len() as u32
+                );
+                reply.extend_from_slice(&context_id.to_be_bytes());
+                reply.extend_from_slice(&(descriptors.len() as u32).to_be_bytes());
+                for (length, state) in descriptors {
+                    reply.extend_from_slice(&length.to_be_bytes());
+                    reply.extend_from_slice(&(state as u64).to_be_bytes());
+                }
+            } else {
+                self.structured_reply(
+                    reply,
+                    reply_flags,
+                    proto::NBD_REPLY_TYPE_BLOCK_STATUS,
+                    handle,
+                    offset,
+                    4 + 8 * descriptors.len() as u32
+                );
+                reply.extend_from_slice(&context_id.to_be_bytes());
+                for (length, state) in descriptors {
+                    reply.extend_from_slice(&(length as u32).to_be_bytes());
+                    reply.extend_from_slice(&state.to_be_bytes());
+                }
+            }
+        }
+    }
+
+    fn reply_data_chunk(&self, reply: &mut Vec<u8>, flags: u16, handle: u64, request_offset: u64, chunk_offset: u64, data: &[u8]) {
+        self.structured_reply(reply, flags, proto::NBD_REPLY_TYPE_OFFSET_DATA, handle, request_offset, 8 + data.len() as u32);
+        reply.extend_from_slice(&chunk_offset.to_be_bytes());
+        reply.extend_from_slice(data);
+    }
+
+    fn reply_done(&self, reply: &mut Vec<u8>, handle: u64, offset: u64) {
+        if self.structured_reply {
+            self.structured_reply(
+                reply,
+                proto::NBD_REPLY_FLAG_DONE,
+                proto::NBD_REPLY_TYPE_NONE,
+                handle,
+                offset,
+                0
+            );
+        } else {
+            self.simple_reply(reply, 0_u32, handle);
+        }
+    }
+
+    fn reply_error(&self, reply: &mut Vec<u8>, handle: u64, offset: u64, errno: u8, message: &str) {
+        if self.structured_reply {
+            let err_msg = message.as_bytes();
+            self.structured_reply(
+                reply,
+                proto::NBD_REPLY_FLAG_DONE,
+                proto::NBD_REPLY_TYPE_ERROR,
+                handle,
+                offset,
+                6 + err_msg.len() as u32
+            );
+            reply.extend_from_slice(&(errno as u32).to_be_bytes());
+            reply.extend_from_slice(&(err_msg.len() as u16).to_be_bytes());
+            reply.extend_from_slice(err_msg);
+        } else {
+            self.simple_reply(reply, errno as u32, handle);
+        }
+    }
+
+    fn reply_io_error(&self, reply: &mut Vec<u8>, handle: u64, offset: u64, err: &Error) {
+        self.reply_error(reply, handle, offset, errno::from_error(err), &err.to_string());
+    }
+
+    // Error chunk pointing at the offset a read failed at, the chunks before it were already sent
+    fn reply_error_offset(&self, reply: &mut Vec<u8>, handle: u64, offset: u64, err_offset: u64, err: &Error) {
+        let err_msg = err.to_string();
+        let err_msg = err_msg.as_bytes();
+        self.structured_reply(
+            reply,
+            proto::NBD_REPLY_FLAG_DONE,
+            proto::NBD_REPLY_TYPE_ERROR_OFFSET,
+            handle,
+            offset,
+            14 + err_msg.len() as u32
+        );
+        reply.extend_from_slice(&(errno::from_error(err) as u32).to_be_bytes());
+        reply.extend_from_slice(&(err_msg.len() as u16).to_be_bytes());
+        reply.extend_from_slice(err_msg);
+        reply.extend_from_slice(&err_offset.to_be_bytes());
+    }
+
+    fn simple_reply(&self, reply: &mut Vec<u8>, err_code: u32, handle: u64) {
+        codec::encode_simple_reply(reply, err_code, handle);
+    }
+
+    // `request_offset` is the offset of the request, echoed in extended headers; the offsets of
+    // data and hole chunks are in their payloads
+    fn structured_reply(&self, reply: &mut Vec<u8>, flags: u16, reply_type: u16, handle: u64, request_offset: u64, length_of_payload: u32) {
+        codec::encode_structured_reply(reply, self.extended_headers, flags, reply_type, handle, request_offset, length_of_payload);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::mpsc, time::Duration};
+    use crate::util::Propagation;
+
+    // its flushes block until released
+    struct BlockingFlush {
+        started: Mutex<mpsc::Sender<()>>,
+        release: Mutex<mpsc::Receiver<()>>,
+    }
+
+    impl BlockStorage for BlockingFlush {
+        fn init(&mut self, _init_volume: bool) -> Result<(), Box<dyn std::error::Error>> { Ok(()) }
+        fn init_volume(&mut self) -> Result<(), Box<dyn std::error::Error>> { Ok(()) }
+        fn check_volume(&mut self) -> Result<(), Box<dyn std::error::Error>> { Ok(()) }
+        fn destroy_volume(&mut self) {}
+        fn get_name(&self) -> String { String::from("blocking") }
+        fn get_volume_size(&self) -> u64 { 1 << 20 }
+
+        fn read(&self, _offset: u64, length: usize) -> Result<Vec<u8>, Error> {
+            Ok(vec![0; length])
+        }
+
+        fn write(&mut self, _offset: u64, _length: usize, _data: &[u8]) -> Result<Propagation, Error> {
+            Ok(Propagation::Guaranteed)
+        }
+
+        fn flush(&self, _offset: u64, _length: usize) -> Result<Propagation, Error> {
+            self.started.lock().unwrap().send(()).unwrap();
+            self.release.lock().unwrap().recv().unwrap();
+            Ok(Propagation::Guaranteed)
+        }
+
+        fn close(&mut self) {}
+    }
+
+    fn request(seq: u64, offset: u64, length: u64, access: Access) -> InFlightRequest {
+        InFlightRequest { seq, offset, length, access }
+    }
+
+    fn nbd_request(req_type: u16, offset: u64, length: u64) -> NBDRequest {
+        NBDRequest { flags: 0, req_type, handle: 1, offset, length, data: Vec::new() }
+    }
+
+    #[test]
+    fn test_conflicts_with() {
+        let write = request(1, 4096, 4096, Access::Write);
+        // overlapping reads and writes keep their order, reads alone don't
+        assert!(write.conflicts_with(&request(2, 0, 8192, Access::Read)));
+        assert!(!write.conflicts_with(&request(2, 8192, 4096, Access::Read)));
+        assert!(!request(1, 0, 8192, Access::Read).conflicts_with(&request(2, 0, 8192, Access::Read)));
+        assert!(request(1, 0, 8192, Access::Read).conflicts_with(&request(2, 4096, 1, Access::Write)));
+        // flushes wait for earlier writes only
+        assert!(write.conflicts_with(&request(2, 0, 0, Access::Flush)));
+        assert!(!request(1, 0, 8192, Access::Read).conflicts_with(&request(2, 0, 0, Access::Flush)));
+        assert!(!request(1, 0, 0, Access::Flush).conflicts_with(&request(2, 0, 4096, Access::Write)));
+        // barriers are ordered against everything
+        assert!(request(1, 1 << 30, 0, Access::Barrier).conflicts_with(&request(2, 0, 4096, Access::Read)));
+        assert!(request(1, 0, 4096, Access::Read).conflicts_with(&request(2, 1 << 30, 0, Access::Barrier)));
+    }
+
+    #[test]
+    fn test_in_flight_turn_completes_on_panic() {
+        let in_flight = Arc::new(InFlight::new());
+        let write = in_flight.register(0, 4096, Access::Write);
+        let read = in_flight.register(0, 4096, Access::Read);
+        let panicked = thread::spawn(move || {
+            write.wait();
+            panic!("request failed");
+        }).join();
+        assert!(panicked.is_err());
+        // returns instead of waiting for the write forever
+        read.wait();
+    }
+
+    #[test]
+    fn test_read_not_blocked_by_flush() {
+        let (started, flush_started) = mpsc::channel();
+        let (release_flush, release) = mpsc::channel();
+        let driver: Box<dyn BlockStorage> = Box::new(BlockingFlush { started: Mutex::new(started), release: Mutex::new(release) });
+        let options = TransmissionOptions {
+            read_only: false,
+            lease: None,
+            structured_reply: false,
+            extended_headers: false,
+            meta_contexts: Vec::new(),
+            block_size: BlockSize::default(),
+            aligned: false,
+        };
+        let transmission = Arc::new(Transmission::new(Arc::new(RwLock::new(driver)), Arc::new(InFlight::new()), options));
+
+        let flush = {
+            let transmission = Arc::clone(&transmission);
+            thread::spawn(move || transmission.execute(&nbd_request(proto::NBD_CMD_FLUSH, 0, 0)))
+        };
+        flush_started.recv().unwrap();
+        let (replied, read_reply) = mpsc::channel();
+        {
+            let transmission = Arc::clone(&transmission);
+            thread::spawn(move || replied.send(transmission.execute(&nbd_request(proto::NBD_CMD_READ, 0, 4096))));
+        }
+        // the simple reply header and the data
+        assert_eq!(read_reply.recv_timeout(Duration::from_secs(10)).unwrap().len(), 16 + 4096);
+        release_flush.send(()).unwrap();
+        assert_eq!(flush.join().unwrap().len(), 16);
+    }
+
+    #[test]
+    fn test_check_request() {
+        let block_size = BlockSize::default();
+        let check = |req_type, offset, length, aligned| {
+            check_request(&nbd_request(req_type, offset, length), 1 << 20, &block_size, aligned).map_err(|(errno, _)| errno)
+        };
+        assert_eq!(check(proto::NBD_CMD_READ, 0, 1 << 20, true), Ok(()));
+        assert_eq!(check(proto::NBD_CMD_READ, 1, 4096, false), Ok(()));
+        assert_eq!(check(proto::NBD_CMD_READ, 1, 4096, true), Err(proto::NBD_EINVAL));
+        assert_eq!(check(proto::NBD_CMD_TRIM, 4096, 100, true), Err(proto::NBD_EINVAL));
+        // past the end, and overflowing ranges
+        assert_eq!(check(proto::NBD_CMD_READ, 1 << 20, 1, false), Err(proto::NBD_EINVAL));
+        assert_eq!(check(proto::NBD_CMD_WRITE, (1 << 20) - 512, 1024, false), Err(proto::NBD_ENOSPC));
+        assert_eq!(check(proto::NBD_CMD_WRITE_ZEROES, u64::MAX, 2, false), Err(proto::NBD_ENOSPC));
+        assert_eq!(check(proto::NBD_CMD_BLOCK_STATUS, u64::MAX - 1, 2, false), Err(proto::NBD_EINVAL));
+        // only reads and writes are limited by the maximum block size
+        let huge = check_request(&nbd_request(proto::NBD_CMD_READ, 0, 64 << 20), 1 << 30, &block_size, false);
+        assert_eq!(huge.map_err(|(errno, _)| errno), Err(proto::NBD_EOVERFLOW));
+        assert!(check_request(&nbd_request(proto::NBD_CMD_TRIM, 0, 64 << 20), 1 << 30, &block_size, false).is_ok());
+        // commands without a range
+        assert_eq!(check(proto::NBD_CMD_FLUSH, 0, 0, true), Ok(()));
+        // empty block status queries, and resizes to zero or to unaligned sizes
+        assert_eq!(check(proto::NBD_CMD_BLOCK_STATUS, 0, 0, false), Err(proto::NBD_EINVAL));
+        assert_eq!(check(proto::NBD_CMD_RESIZE, 1 << 30, 0, true), Ok(()));
+        assert_eq!(check(proto::NBD_CMD_RESIZE, 0, 0, false), Err(proto::NBD_EINVAL));
+        assert_eq!(check(proto::NBD_CMD_RESIZE, (1 << 30) + 1, 0, true), Err(proto::NBD_EINVAL));
+        assert_eq!(check(proto::NBD_CMD_RESIZE, (1 << 30) + 1, 0, false), Ok(()));
+    }
+}
@@ -4,7 +4,7 @@ use std::{
     collections::{HashMap},
     ops::{Deref},
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         mpsc::{Sender, channel},
         Arc, RwLock, Mutex
     },
@@ -24,7 +24,7 @@ use crate::object::{
     StreamingPartialAccessObjectStorage,
     ObjectMeta,
 };
-use crate::util::Propagation;
+use crate::util::{Propagation, Extent};
 
 pub struct CachedObject {
     data: Vec<u8>,
@@ -96,7 +96,9 @@ impl Deref for CacheMapRef {
 }
 
 pub struct CacheBackend {
+    #[allow(dead_code)]
     config: String,
+    read_only: bool,
     read_backend: Arc<Mutex<Box<dyn ObjectStorage>>>,
     write_backend: Arc<Mutex<Box<dyn ObjectStorage>>>,
     cache: CacheMapRef,
@@ -104,14 +106,16 @@ pub struct CacheBackend {
     mem_limit: usize,
     stall_secs: u16,
     bgthread_jh: Option<thread::JoinHandle<()>>,
-    sender: Option<Sender<bool>>
+    sender: Option<Sender<bool>>,
+    // see `fence`
+    fenced: Arc<AtomicBool>,
 }
 
 impl CacheBackend {
-    pub fn new(config: String) -> CacheBackend {
+    pub fn new(config: String, read_only: bool) -> CacheBackend {
         let mut split: Vec<&str> = config.split(",").collect();
         let backend_url = split.pop().unwrap();
-        let parsed_url = Url::parse(&backend_url)
+        let _parsed_url = Url::parse(&backend_url)
             .expect("Failed to parse backend (URL)");
 
         // TODO: Parse remaining parts from split for configuring;
@@ -120,14 +124,16 @@ impl CacheBackend {
 
         let mut obj = CacheBackend {
             config: config.clone(),
-            read_backend: Arc::new(Mutex::new(object_storage_with_config(config.clone()).unwrap())),
-            write_backend: Arc::new(Mutex::new(object_storage_with_config(config.clone()).unwrap())),
+            read_only,
+            read_backend: Arc::new(Mutex::new(object_storage_with_config(config.clone(), read_only).unwrap())),
+            write_backend: Arc::new(Mutex::new(object_storage_with_config(config.clone(), read_only).unwrap())),
             cache: CacheMapRef::new(),
             mem_usage: Arc::new(AtomicUsize::new(0)),
             mem_limit: 128 * 1024 * 1024,
             stall_secs: 3, // persist things to disk after 3 seconds
             bgthread_jh: None,
             sender: None,
+            fenced: Arc::new(AtomicBool::new(false)),
         };
         obj.start_persister();
         obj
@@ -137,10 +143,11 @@ impl CacheBackend {
 
     fn start_persister(&mut self) {
         let cache_ref = self.cache.clone();
-        let mem_limit = self.mem_limit;
+        let _mem_limit = self.mem_limit;
         let stall_secs = self.stall_secs;
         let mem_usage = Arc::clone(&self.mem_usage);
         let write_backend = Arc::clone(&self.write_backend);
+        let fenced = Arc::clone(&self.fenced);
         let (send, rcv) = channel();
         self.sender = Some(send);
 
@@ -150,18 +157,18 @@ impl CacheBackend {
                     let cache = cache_ref.read().unwrap();
                     let total_pages = cache.len();
                     let unwritten_pages = cache.iter()
-                        .filter(|(k, cref)| {
+                        .filter(|(_k, cref)| {
                             let c = cref.read().unwrap();
                             c.writes > c.persists
                         }) // only not-persisted ones
                         .count();
 
                     let oldest_unwritten_page = cache.iter()
-                        .filter(|(k, cref)| {
+                        .filter(|(_k, cref)| {
                             let c = cref.read().unwrap();
                             c.writes > c.persists && c.last_write.unwrap().elapsed() > Duration::from_secs(stall_secs.into())
                         }) // only not-persisted ones, after `stall_secs`
-                        .min_by(|(xk, xref), (yk, yref)| {
+                        .min_by(|(_xk, xref), (_yk, yref)| {
                             let x = xref.read().unwrap();
                             let y = yref.read().unwrap();
                             x.last_write.unwrap().cmp(&y.last_write.unwrap())
@@ -187,6 +194,10 @@ impl CacheBackend {
                     drop(cache);
 
                     let backend = write_backend.lock().unwrap();
+                    // picked before `fence` dropped it
+                    if fenced.load(Ordering::SeqCst) {
+                        continue;
+                    }
                     let write_res = backend.write(obj_name.clone(), &cache_obj.data.clone());
                     if write_res.is_ok() {
                         let persist_res = backend.persist_object(obj_name.clone());
@@ -206,14 +217,28 @@ impl CacheBackend {
             .unwrap());
     }
 
+    fn check_writable(&self) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::new(ErrorKind::PermissionDenied, "Object storage is read-only"));
+        }
+        self.check_fenced()
+    }
+
+    fn check_fenced(&self) -> Result<(), Error> {
+        if self.fenced.load(Ordering::SeqCst) {
+            return Err(Error::new(ErrorKind::PermissionDenied, "Object storage was taken over by another writer"));
+        }
+        Ok(())
+    }
+
     fn least_important_cache_key(cache: &CacheMap) -> Option<String> {
         let kvpair = cache
             .iter()
-            .filter(|(k, cref)| {
+            .filter(|(_k, cref)| {
                 let c = cref.read().unwrap();
                 c.writes <= c.persists
             }) // only already persisted ones
-            .min_by(|(xk, xref), (yk, yref)| {
+            .min_by(|(_xk, xref), (_yk, yref)| {
                 let x = xref.read().unwrap();
                 let y = yref.read().unwrap();
                 if x.last_read.is_none() {
@@ -227,7 +252,7 @@ impl CacheBackend {
             });
 
         if kvpair.is_some() {
-            let (k, vref) = kvpair.unwrap();
+            let (k, _vref) = kvpair.unwrap();
             return Some(k.to_string());
         }
 
@@ -240,7 +265,7 @@ impl CacheBackend {
             return None;
         }
 
-        let (kref, cref) = cache_entry.unwrap();
+        let (_kref, cref) = cache_entry.unwrap();
         Some(cref.clone())
     }
 
@@ -298,7 +323,7 @@ where
 }
 
 impl SimpleObjectStorage for CacheBackend {
-    fn init(&mut self, conn_str: String) {
+    fn init(&mut self, _conn_str: String) {
         // .. noop
         log::info!("init");
     }
@@ -357,13 +382,16 @@ impl SimpleObjectStorage for CacheBackend {
         };
 
         let mut cache = self.cache.write().unwrap();
-        self.ensure_free_memory(cache.deref_mut(), cached_object.size);
+        if let Err(e) = self.ensure_free_memory(cache.deref_mut(), cached_object.size) {
+            log::debug!("{}, going over the memory limit", e);
+        }
         self.mem_usage.fetch_add(cached_object.size, Ordering::Release);
         cache.insert(object_name.clone(), CacheValRef::new(cached_object));
         Ok(data)
     }
 
     fn write(&self, object_name: String, data: &[u8]) -> Result<Propagation, Error> {
+        self.check_writable()?;
         let mut cache = self.cache.write().unwrap();
         let cached_obj_ref = cache.get_key_value(&object_name.clone());
         if cached_obj_ref.is_some() {
@@ -373,7 +401,7 @@ impl SimpleObjectStorage for CacheBackend {
             cached_obj.last_write = Some(Instant::now());
             cached_obj.data = data.to_vec();
             log::trace!("mem_usage: {}", self.mem_usage.load(Ordering::Acquire));
-            self.sender.as_ref().unwrap().send(true);
+            let _ = self.sender.as_ref().unwrap().send(true);
             return Ok(Propagation::Queued);
         }
 
@@ -391,15 +419,18 @@ impl SimpleObjectStorage for CacheBackend {
         };
         // TODO: Check if mem limit allows this, otherwise
         // * block until it allows, and pressure cache purge
-        self.ensure_free_memory(cache.deref_mut(), cached_object.size);
+        if let Err(e) = self.ensure_free_memory(cache.deref_mut(), cached_object.size) {
+            log::debug!("{}, going over the memory limit", e);
+        }
         self.mem_usage.fetch_add(cached_object.size, Ordering::Release);
         log::trace!("mem_usage: {}", self.mem_usage.load(Ordering::Acquire));
         cache.insert(object_name.clone(), CacheValRef::new(cached_object));
-        self.sender.as_ref().unwrap().send(true);
+        let _ = self.sender.as_ref().unwrap().send(true);
         Ok(Propagation::Queued)
     }
 
     fn delete(&self, object_name: String) -> Result<Propagation, Error> {
+        self.check_writable()?;
         let mut cache = self.cache.write().unwrap();
         let cached_obj_ref = cache.get_key_value(&object_name.clone());
         if cached_obj_ref.is_some() {
@@ -412,6 +443,16 @@ impl SimpleObjectStorage for CacheBackend {
         self.write_backend.lock().unwrap().delete(object_name.clone())
     }
 
+    // bypasses the cache, so it is as atomic as the backend
+    fn replace(&self, object_name: String, data: &[u8]) -> Result<Propagation, Error> {
+        self.check_writable()?;
+        let mut cache = self.cache.write().unwrap();
+        if let Some(cached_obj) = cache.remove(&object_name) {
+            self.mem_usage.fetch_sub(cached_obj.read().unwrap().size, Ordering::Release);
+        }
+        self.write_backend.lock().unwrap().replace(object_name, data)
+    }
+
     fn get_size(&self, object_name: String) -> Result<u64, Error> {
         let cache = self.cache.read().unwrap();
         let cached_obj_ref = cache.get_key_value(&object_name.clone());
@@ -466,46 +507,121 @@ impl SimpleObjectStorage for CacheBackend {
         self.write_backend.lock().unwrap().end_operations_on_object(object_name.clone())
     }
 
-    fn persist_object(&self, object_name: String) -> Result<Propagation, Error> {
+    fn resize_object(&self, object_name: String, len: u64) -> Result<(), Error> {
+        self.check_writable()?;
         let cache = self.cache.write().unwrap();
         let cached_obj_ref = cache.get_key_value(&object_name.clone());
-        let backend = self.write_backend.lock().unwrap();
         if cached_obj_ref.is_some() {
+            // keep the cached copy in line, so the persister doesn't write the old size back
             let mut cached_obj = cached_obj_ref.unwrap().1.write().unwrap();
+            self.mem_usage.fetch_sub(cached_obj.size, Ordering::Release);
+            cached_obj.data.resize(len as usize, 0);
+            cached_obj.size = len as usize;
+            self.mem_usage.fetch_add(cached_obj.size, Ordering::Release);
+            log::trace!("mem_usage: {}", self.mem_usage.load(Ordering::Acquire));
+        }
+        drop(cache);
+
+        self.write_backend.lock().unwrap().resize_object(object_name, len)
+    }
+
+    fn object_extents(&self, object_name: String, offset: u64, length: usize) -> Result<Vec<Extent>, Error> {
+        if self.get_cache(object_name.clone()).is_some() {
+            // cached objects may hold unpersisted data, report all of it
+            return Ok(vec![Extent::data(length as u64)]);
+        }
+        self.read_backend.lock().unwrap().object_extents(object_name, offset, length)
+    }
+
+    fn prefetch_object(&self, object_name: String, _offset: u64, _length: usize) -> Result<(), Error> {
+        if self.get_cache(object_name.clone()).is_some() {
+            log::trace!("prefetch: hit");
+            return Ok(());
+        }
+        // for side effect; objects are always cached as a whole
+        log::trace!("prefetch: miss");
+        self.read(object_name)?;
+        Ok(())
+    }
 
-            // already persisted
+    fn persist_object(&self, object_name: String) -> Result<Propagation, Error> {
+        self.check_fenced()?;
+        // only the object stays locked while it is written back, not the whole cache
+        let cached_obj_ref = self.cache.read().unwrap().get(&object_name).map(|cref| Arc::clone(cref));
+        if let Some(cached_obj_ref) = cached_obj_ref {
+            let mut cached_obj = cached_obj_ref.write().unwrap();
+            let backend = self.write_backend.lock().unwrap();
+            // dropped by `fence` in the meantime
+            self.check_fenced()?;
+
+            // already written back, only make sure the backend made it durable
             if cached_obj.persists == cached_obj.writes {
-                return Ok(Propagation::Redundant);
+                return retry(|| { backend.persist_object(object_name.clone()) });
             }
 
             log::debug!("persist: hit");
             let write_propagation = retry(|| {
                 backend.write(object_name.clone(), &cached_obj.data.clone())
             })?;
-            cached_obj.persists = cached_obj.writes;
-            cached_obj.last_persist = Some(Instant::now());
 
             let persist_propagation = retry(|| {
                 backend.persist_object(object_name.clone())
             })?;
+            cached_obj.persists = cached_obj.writes;
+            cached_obj.last_persist = Some(Instant::now());
 
-            if (persist_propagation as u8) > (write_propagation as u8) {
+            // the object is only as durable as the weakest of both steps
+            if (persist_propagation as u8) < (write_propagation as u8) {
                 return Ok(persist_propagation);
             }
 
             return Ok(write_propagation);
         }
 
+        let backend = self.write_backend.lock().unwrap();
         retry(|| { backend.persist_object(object_name.clone()) })
     }
 
+    // Drops the objects that weren't written back yet, and stops writing back
+    fn fence(&self) {
+        self.fenced.store(true, Ordering::SeqCst);
+        let mut cache = self.cache.write().unwrap();
+        let cached = cache.len();
+        cache.retain(|_, cref| {
+            let c = cref.read().unwrap();
+            if c.writes > c.persists {
+                self.mem_usage.fetch_sub(c.size, Ordering::Release);
+                return false;
+            }
+            true
+        });
+        log::warn!("object::cache fenced, dropped {} unwritten objects", cached - cache.len());
+        drop(cache);
+        // waits for a write back that was already in progress
+        drop(self.write_backend.lock().unwrap());
+    }
+
     fn close(&mut self) {
         log::debug!("object::cache::close");
-        self.sender.as_ref().unwrap().send(false);
+        let _ = self.sender.as_ref().unwrap().send(false);
         match self.bgthread_jh.take() {
             Some(bgthread) => bgthread.join().unwrap(),
             None => log::debug!("no thread!")
         };
+        // the persister only writes objects that stalled, write out the rest before they're lost
+        let dirty: Vec<String> = self.cache.read().unwrap()
+            .iter()
+            .filter(|(_, cref)| {
+                let c = cref.read().unwrap();
+                c.writes > c.persists
+            })
+            .map(|(k, _)| k.to_string())
+            .collect();
+        for object_name in dirty {
+            if let Err(e) = self.persist_object(object_name.clone()) {
+                log::error!("Couldn't persist {} on close: {}", object_name, e);
+            }
+        }
     }
 }
 
@@ -546,6 +662,7 @@ impl PartialAccessObjectStorage for CacheBackend {
     }
 
     fn partial_write(&self, object_name: String, offset: u64, length: usize, data: &[u8]) -> Result<Propagation, Error> {
+        self.check_writable()?;
         let cache = self.cache.read().unwrap();
         if !cache.contains_key(&object_name.clone()) {
             // code below commented out, because if we proxy this to backend,
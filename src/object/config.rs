@@ -5,7 +5,7 @@ use crate::object::FileBackend;
 use crate::object::S3Backend;
 use crate::object::CacheBackend;
 
-pub fn object_storage_with_config(config: String) -> Result<Box<dyn ObjectStorage>, Error> {
+pub fn object_storage_with_config(config: String, read_only: bool) -> Result<Box<dyn ObjectStorage>, Error> {
     // config sample; "file:/path/to/folder/"
     // config sample; "s3:http://localhost:9000/test"
     // config sample; "cache:s3:http://localhost:9000/test"
@@ -18,13 +18,13 @@ pub fn object_storage_with_config(config: String) -> Result<Box<dyn ObjectStorag
 
     return match driver_name {
         "file" => {
-            Ok(Box::new(FileBackend::new(driver_config.replace("///", "/"))))
+            Ok(Box::new(FileBackend::new(driver_config.replace("///", "/"), read_only)))
         },
         "s3" => {
             Ok(Box::new(S3Backend::new(driver_config)))
         },
         "cache" => {
-            Ok(Box::new(CacheBackend::new(driver_config)))
+            Ok(Box::new(CacheBackend::new(driver_config, read_only)))
         },
         _ => {
             // hard fail
@@ -33,12 +33,12 @@ pub fn object_storage_with_config(config: String) -> Result<Box<dyn ObjectStorag
     };
 }
 
-pub fn object_storages_with_config(config: String) -> Result<Vec<Box<dyn ObjectStorage>>, Error> {
+pub fn object_storages_with_config(config: String, read_only: bool) -> Result<Vec<Box<dyn ObjectStorage>>, Error> {
     let split: Vec<&str> = config.split(",").collect();
     let mut object_storages:Vec<Box<dyn ObjectStorage>> = Vec::new();
 
     for cfg in split {
-        let object_storage = object_storage_with_config(String::from(cfg))?;
+        let object_storage = object_storage_with_config(String::from(cfg), read_only)?;
         object_storages.push(object_storage);
     }
     Ok(object_storages)
@@ -1,5 +1,5 @@
 use std::{
-    fs::{File, OpenOptions, remove_file},
+    fs::{File, OpenOptions, remove_file, rename},
     io::{Read, Write, Seek, SeekFrom, Error, ErrorKind},
     collections::{HashMap},
     sync::{Arc,RwLock},
@@ -20,21 +20,22 @@ use crate::object::{
     StreamingPartialAccessObjectStorage,
     ObjectMeta,
 };
-use crate::util::Propagation;
+use crate::util::{Propagation, Extent, push_extent};
 
 pub struct FileBackend {
     folder_path: String,
+    read_only: bool,
     open_files: RwLock<HashMap<String, Arc<RwLock<MappedFile>>>>,
 }
 
 impl Default for FileBackend {
     fn default() -> FileBackend {
-        FileBackend::new(String::from("./"))
+        FileBackend::new(String::from("./"), false)
     }
 }
 
 impl FileBackend {
-    pub fn new(config: String) -> FileBackend {
+    pub fn new(config: String, read_only: bool) -> FileBackend {
         log::debug!("FileBackend.config: {:?}", &config);
         let path = Path::new(config.as_str());
         if !path.exists() {
@@ -47,6 +48,7 @@ impl FileBackend {
         }
         FileBackend {
             folder_path: config.clone(),
+            read_only,
             open_files: RwLock::<HashMap<String, Arc<RwLock<MappedFile>>>>::new(
                 HashMap::<String, Arc<RwLock<MappedFile>>>::new()
             )
@@ -56,9 +58,16 @@ impl FileBackend {
     fn open_file(&self, object_name: String, create: bool) -> Result<File, Error> {
         OpenOptions::new()
             .read(true)
-            .write(true)
+            .write(!self.read_only)
             .create(create)
-            .open(object_name.clone())
+            .open(self.obj_path(object_name))
+    }
+
+    fn check_writable(&self) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::new(ErrorKind::PermissionDenied, "Object storage is read-only"));
+        }
+        Ok(())
     }
 
     fn get_file(&self, object_name: String) -> Result<Arc<RwLock<MappedFile>>, Error> {
@@ -70,7 +79,7 @@ impl FileBackend {
             return Ok(Arc::clone(&mapped_file.unwrap().1));
         }
 
-        let mapped_refcell = Arc::new(RwLock::new(MappedFile::open(self.obj_path(object_name.clone()))?));
+        let mapped_refcell = Arc::new(RwLock::new(MappedFile::new(self.open_file(object_name.clone(), false)?)?));
         let mapped = mapped_refcell.clone();
         open_files.insert(object_name.clone(), mapped_refcell);
         Ok(mapped)
@@ -110,6 +119,7 @@ impl SimpleObjectStorage for FileBackend {
     }
     
     fn create_object(&self, object_name: String, len: u64) -> Result<(), Error> {
+        self.check_writable()?;
         let path = self.obj_path(object_name.clone());
         let mut file = File::create(path)?;
         file.seek(SeekFrom::Start(len - 1))?;
@@ -190,6 +200,7 @@ impl SimpleObjectStorage for FileBackend {
     }
 
     fn write(&self, object_name: String, data: &[u8]) -> Result<Propagation, Error> {
+        self.check_writable()?;
         let open_files = self.open_files.read().unwrap();
         match open_files.get_key_value(&object_name) {
             Some(mapped_file) => {
@@ -216,10 +227,26 @@ impl SimpleObjectStorage for FileBackend {
     }
 
     fn delete(&self, object_name: String) -> Result<Propagation, Error> {
+        self.check_writable()?;
         remove_file(self.obj_path(object_name))?;
         Ok(Propagation::Guaranteed)
     }
 
+    // written to a temporary file first, then renamed over the object
+    fn replace(&self, object_name: String, data: &[u8]) -> Result<Propagation, Error> {
+        self.check_writable()?;
+        let tmp_path = self.obj_path(format!(".{}.tmp", object_name));
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        rename(&tmp_path, self.obj_path(object_name))?;
+        Ok(Propagation::Guaranteed)
+    }
+
     fn get_size(&self, object_name: String) -> Result<u64, Error> {
         let path = self.obj_path(object_name.clone());
         log::debug!("Getting size of {:?}", path);
@@ -252,7 +279,7 @@ impl SimpleObjectStorage for FileBackend {
     }
 
     fn start_operations_on_object(&self, object_name: String) -> Result<(), Error> {
-        self.get_file(object_name);
+        self.get_file(object_name)?;
         //let mut open_files = self.open_files.write().unwrap();
         // TODO: Check if self.openFiles already has same file, use Rc.increment_strong_count in that case
         // TODO: Mmap? MappedFile::new(f).expect("Something went wrong");
@@ -277,24 +304,23 @@ impl SimpleObjectStorage for FileBackend {
     }
 
     fn persist_object(&self, object_name: String) -> Result<Propagation, Error> {
-        unsafe{ libc::sync(); }
-        // This is only relevant for already open files.
-
-        // let file_refcell = self.get_file(object_name.clone()).unwrap();
-        // let mut mut_pointer = file_refcell
-        //     .write().unwrap()
-        //     .into_mut_mapping(0, self.get_size(object_name.clone()).unwrap() as usize)
-        //     .map_err(|(e, _)| e)
-        //     .unwrap();
-        // mut_pointer.flush();
+        // fsync only the object itself, instead of the whole system
+        let file = match OpenOptions::new().read(true).open(self.obj_path(object_name)) {
+            Ok(file) => file,
+            // nothing was ever written, so there is nothing to persist
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Propagation::Guaranteed),
+            Err(err) => return Err(err),
+        };
+        file.sync_all()?;
         Ok(Propagation::Guaranteed)
     }
 
     fn trim_object (&self, object_name: String, offset: u64, length: usize) -> Result<Propagation, Error> { //hints fallocate
+        self.check_writable()?;
         #[cfg(target_os = "linux")]
         {
             let mut open_files = self.open_files.write().unwrap();
-            let mmap_file = open_files.remove_entry(&object_name);
+            let _mmap_file = open_files.remove_entry(&object_name);
             let path = self.obj_path(object_name.clone());
             let file = OpenOptions::new()
                 .write(true)
@@ -312,6 +338,83 @@ impl SimpleObjectStorage for FileBackend {
         Err(Error::new(ErrorKind::Unsupported, "Trim Not Supported"))
     }
 
+    fn resize_object(&self, object_name: String, len: u64) -> Result<(), Error> { // hints ftruncate
+        self.check_writable()?;
+        // drop the mapping, it is re-created with the new size on next access
+        let mut open_files = self.open_files.write().unwrap();
+        let mapped_file = open_files.remove(&object_name);
+        let file = OpenOptions::new()
+            .write(true)
+            .open(self.obj_path(object_name.clone()))?;
+        file.set_len(len)?;
+        if mapped_file.is_some() {
+            open_files.insert(
+                object_name.clone(),
+                Arc::new(RwLock::new(MappedFile::new(self.open_file(object_name, false)?)?))
+            );
+        }
+        Ok(())
+    }
+
+    fn object_extents(&self, object_name: String, offset: u64, length: usize) -> Result<Vec<Extent>, Error> { // hints lseek(SEEK_DATA/SEEK_HOLE)
+        #[cfg(target_os = "linux")]
+        {
+            let file = OpenOptions::new()
+                .read(true)
+                .open(self.obj_path(object_name))?;
+            let fd = file.as_raw_fd();
+            let end = offset + length as u64;
+            let mut extents: Vec<Extent> = Vec::new();
+            let mut pos = offset;
+            while pos < end {
+                let data = unsafe { libc::lseek(fd, pos as libc::off_t, libc::SEEK_DATA) };
+                if data < 0 {
+                    let err = Error::last_os_error();
+                    if err.raw_os_error() != Some(libc::ENXIO) {
+                        return Err(err);
+                    }
+                    // no data till the end of the file
+                    push_extent(&mut extents, Extent::hole(end - pos));
+                    break;
+                }
+                let data = std::cmp::min(data as u64, end);
+                push_extent(&mut extents, Extent::hole(data - pos));
+                if data == end {
+                    break;
+                }
+                let hole = unsafe { libc::lseek(fd, data as libc::off_t, libc::SEEK_HOLE) };
+                if hole < 0 {
+                    return Err(Error::last_os_error());
+                }
+                let hole = std::cmp::min(hole as u64, end);
+                push_extent(&mut extents, Extent::data(hole - data));
+                pos = hole;
+            }
+            Ok(extents)
+        }
+        #[cfg(not(target_os = "linux"))]
+        Err(Error::new(ErrorKind::Unsupported, "Extents Not Supported"))
+    }
+
+    fn prefetch_object(&self, object_name: String, offset: u64, length: usize) -> Result<(), Error> { // hints readahead
+        #[cfg(target_os = "linux")]
+        {
+            let file = OpenOptions::new()
+                .read(true)
+                .open(self.obj_path(object_name))?;
+            let result = unsafe { libc::posix_fadvise(
+                file.as_raw_fd(),
+                offset as libc::off_t,
+                length as libc::off_t,
+                libc::POSIX_FADV_WILLNEED
+            ) };
+            if result != 0 {
+                return Err(Error::from_raw_os_error(result));
+            }
+        }
+        Ok(())
+    }
+
     fn close(&mut self) {
         log::debug!("object::file::close");
     }
@@ -351,11 +454,12 @@ impl PartialAccessObjectStorage for FileBackend {
     }
 
     fn partial_write(&self, object_name: String, offset: u64, length: usize, data: &[u8]) -> Result<Propagation, Error> {
+        self.check_writable()?;
         let open_files = self.open_files.read().unwrap();
         match open_files.get_key_value(&object_name) {
             Some(mapped_file) => {
                 let mut mapped_file_ptr = mapped_file.1.write().unwrap();
-                let size = mapped_file_ptr.size() as usize;
+                let _size = mapped_file_ptr.size() as usize;
                 let mut mut_pointer = mapped_file_ptr
                     .map_mut(offset, length)
                     .unwrap();
@@ -402,7 +506,7 @@ mod tests {
                             .create(true)
                             .open(Path::new(&folder.path).join(dummy_file_name.clone()))
                             .unwrap();
-        dummy_file.write(&[0_u8; 1024]);
+        dummy_file.write(&[0_u8; 1024]).unwrap();
         let filesystem = FileBackend {
             folder_path: folder.path.clone(),
             ..FileBackend::default()
@@ -423,7 +527,7 @@ mod tests {
                             .create(true)
                             .open(Path::new(&folder.path).join(dummy_file_name.clone()))
                             .unwrap();
-        dummy_file.write(&[0_u8; 1024]);
+        dummy_file.write(&[0_u8; 1024]).unwrap();
         let mut filesystem = FileBackend::default();
 
         assert!(&filesystem.folder_path == "./");
@@ -440,13 +544,13 @@ mod tests {
                             .create(true)
                             .open(Path::new(&folder.path).join(dummy_file_name.clone()))
                             .unwrap();
-        dummy_file.write(&[0_u8; 1024]);
+        dummy_file.write(&[0_u8; 1024]).unwrap();
         let filesystem = FileBackend {
             folder_path: folder.path.clone(),
             ..FileBackend::default()
         };
 
-        filesystem.start_operations_on_object(dummy_file_name.clone());
+        filesystem.start_operations_on_object(dummy_file_name.clone()).unwrap();
     }
 
     #[test]
@@ -458,13 +562,24 @@ mod tests {
                             .create(true)
                             .open(Path::new(&folder.path).join(dummy_file_name.clone()))
                             .unwrap();
-        dummy_file.write(&[0_u8; 1024]);
+        dummy_file.write(&[0_u8; 1024]).unwrap();
         let filesystem = FileBackend {
             folder_path: folder.path.clone(),
             ..FileBackend::default()
         };
 
-        filesystem.start_operations_on_object(dummy_file_name.clone());
-        filesystem.end_operations_on_object(dummy_file_name.clone());
+        filesystem.start_operations_on_object(dummy_file_name.clone()).unwrap();
+        filesystem.end_operations_on_object(dummy_file_name.clone()).unwrap();
+    }
+
+    #[test]
+    fn test_file_backend_replace() {
+        let folder = TempFolder::new();
+        let filesystem = FileBackend::new(folder.path.clone(), false);
+
+        filesystem.write(String::from("size"), b"16777216").unwrap();
+        filesystem.replace(String::from("size"), b"4096").unwrap();
+        assert_eq!(filesystem.read(String::from("size")).unwrap(), b"4096");
+        assert!(!Path::new(&folder.path).join(".size.tmp").exists());
     }
 }
@@ -15,8 +15,10 @@ pub use self::s3::S3Backend;
 mod cache;
 pub use self::cache::CacheBackend;
 
-use crate::util::Propagation;
+use crate::util::{Propagation, Extent};
 
+// default implementations ignore their arguments
+#[allow(unused_variables)]
 pub trait SimpleObjectStorage {
     fn init     (&mut self, conn_str: String);
 
@@ -41,6 +43,11 @@ pub trait SimpleObjectStorage {
     fn read     (&self, object_name: String) -> Result<Vec<u8>, Error>;
     fn write    (&self, object_name: String, data: &[u8]) -> Result<Propagation, Error>;
     fn delete   (&self, object_name: String) -> Result<Propagation, Error>;
+    // readers see either the old or the new object, even if the write is interrupted; whole
+    // object writes of most backends already are
+    fn replace  (&self, object_name: String, data: &[u8]) -> Result<Propagation, Error> {
+        self.write(object_name, data)
+    }
 
     // Hint interface (Optional, Default=Noop)
     // hints the object storage backend about long access on object, so the backend can do stuff like MMAP
@@ -50,6 +57,19 @@ pub trait SimpleObjectStorage {
     fn trim_object                (&self, object_name: String, offset: u64, length: usize) -> Result<Propagation, Error> { //hints fallocate
         Err(Error::new(ErrorKind::Unsupported, "Trim Not Supported"))
     }
+    fn prefetch_object            (&self, object_name: String, offset: u64, length: usize) -> Result<(), Error> { // hints readahead
+        Ok(())
+    }
+    fn resize_object              (&self, object_name: String, len: u64) -> Result<(), Error> { // hints ftruncate
+        Err(Error::new(ErrorKind::Unsupported, "Resize Not Supported"))
+    }
+    fn object_extents             (&self, object_name: String, offset: u64, length: usize) -> Result<Vec<Extent>, Error> { // hints lseek(SEEK_DATA/SEEK_HOLE)
+        Err(Error::new(ErrorKind::Unsupported, "Extents Not Supported"))
+    }
+    // another writer took over the storage (see `block::Lease`), writes that didn't reach the
+    // backend yet must never do so
+    fn fence                      (&self) {
+    }
     fn close                      (&mut self);
 }
 
@@ -62,6 +82,7 @@ pub trait PartialAccessObjectStorage {
 }
 
 // With given stream, read `length` bytes, and write to target object, avoids buffering on consumer side
+#[allow(unused_variables)]
 pub trait StreamingObjectStorage {
     // TODO: these can also have dumb default implementations
     fn read_into  (&self, object_name: String, stream: Box<dyn Write>) -> Result<usize, Error> {
@@ -72,6 +93,7 @@ pub trait StreamingObjectStorage {
     }
 }
 
+#[allow(unused_variables)]
 pub trait StreamingPartialAccessObjectStorage {
     // TODO: these can also have dumb default implementations
     fn partial_read_into  (&self, object_name: String, stream: Box<dyn Write>, offset: u64, length: usize) -> Result<usize, Error> {
@@ -82,7 +104,7 @@ pub trait StreamingPartialAccessObjectStorage {
     }
 }
 
-pub trait ObjectStorage: SimpleObjectStorage + PartialAccessObjectStorage + StreamingObjectStorage + StreamingPartialAccessObjectStorage + Send {}
+pub trait ObjectStorage: SimpleObjectStorage + PartialAccessObjectStorage + StreamingObjectStorage + StreamingPartialAccessObjectStorage + Send + Sync {}
 
 #[derive(Debug)]
 pub struct ObjectMeta {
@@ -1,10 +1,4 @@
-//TODO: Convert read & write functions to macros, so usage of these stuff will be simplified
-// util::read_u16(clone_stream!(socket)) ---> read_u16!(socket)
-
-#![macro_use]
 #![allow(dead_code)]
-use std::io::{Read, Write};
-use std::net::TcpStream;
 use regex::Regex;
 
 #[repr(u8)]
@@ -23,99 +17,43 @@ pub enum Propagation {
    // Failed      : u8 = 0,   // Instead of this, Result/Err should be used
 }
 
-/*
-macro_rules! convert_ux_to_bytes {
-    ($ty:ty, $num:expr) => {{
-        let size = ::core::mem::size_of::<$ty>();
-        println!("{:?}", size);
-        let mut bytes = Vec::with_capacity(size as usize);
-        for i in 0..size {
-            bytes[i] = (($num >> (size - i - 1) * 8) & 0xff) as u8;
-        }
-        println!("{:?}", bytes);
-        bytes
-    }}
-}
-*/
-
-macro_rules! read_x_bytes {
-    ($ty:ty, $size:expr, $socket:expr) => {{
-        assert_eq!($size, ::core::mem::size_of::<$ty>());
-        let mut data = [0 as u8; $size];
-        $socket.read(&mut data).expect("Error on reading client.");
-        <$ty>::from_be_bytes(data)
-    }};
-}
-
-macro_rules! read_string {
-    ($size:expr, $socket:expr) => {{
-        assert!($size > 0);
-        let mut data = vec![0; $size as usize];
-        $socket
-            .read_exact(&mut data)
-            .expect("Error on reading client.");
-        <[u8]>::make_ascii_uppercase(&mut data[..]);
-        String::from_utf8_lossy(&data).to_string()
-    }};
-}
-
-macro_rules! write_x_bytes {
-    ($ty:ty, $num:expr, $socket:expr) => {{
-        assert_eq!(
-            ::core::mem::size_of_val(&$num),
-            ::core::mem::size_of::<$ty>()
-        );
-        let data = <$ty>::to_be_bytes($num);
-        $socket.write(&data).expect("Error writing to client.");
-    }};
-}
-
-macro_rules! write {
-    ($buf:expr, $socket:expr) => {{
-        $socket.write($buf).expect("Error on writing data")
-    }};
-}
-
-/*
-pub fn convert_u64_to_bytes(num: u64) -> Vec<u8> {
-    convert_ux_to_bytes!(u64, num)
-}
-*/
-
-pub fn read_u8(mut socket: &TcpStream) -> u8 {
-    read_x_bytes!(u8, 1, socket)
-}
-
-pub fn read_u16(mut socket: &TcpStream) -> u16 {
-    read_x_bytes!(u16, 2, socket)
-}
-
-pub fn read_u32(mut socket: &TcpStream) -> u32 {
-    read_x_bytes!(u32, 4, socket)
-}
-
-pub fn read_u64(mut socket: &TcpStream) -> u64 {
-    read_x_bytes!(u64, 8, socket)
-}
-
-pub fn read_string(size: usize, socket: &mut TcpStream) -> String {
-    read_string!(size, socket)
+impl Propagation {
+    // Whether the data is safe to acknowledge for FLUSH / FUA, i.e. nothing is left queued on our end
+    pub fn is_durable(&self) -> bool {
+        (*self as u8) >= (Propagation::Complete as u8)
+    }
 }
 
-pub fn write_u8(num: u8, socket: &mut TcpStream) {
-    write_x_bytes!(u8, num, socket)
+// Allocation status of a contiguous range, as reported by `BlockStorage::block_status`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Extent {
+    pub length: u64,
+    pub hole: bool, // not allocated on the storage
+    pub zero: bool, // reads as zeroes
 }
 
-pub fn write_u16(num: u16, socket: &mut TcpStream) {
-    write_x_bytes!(u16, num, socket)
-}
+impl Extent {
+    pub fn data(length: u64) -> Extent {
+        Extent { length, hole: false, zero: false }
+    }
 
-pub fn write_u32(num: u32, socket: &mut TcpStream) {
-    write_x_bytes!(u32, num, socket)
+    pub fn hole(length: u64) -> Extent {
+        Extent { length, hole: true, zero: true }
+    }
 }
 
-pub fn write_u64(num: u64, socket: &mut TcpStream) {
-    write_x_bytes!(u64, num, socket)
+// Appends `extent`, merging it into the last one when they have the same status
+pub fn push_extent(extents: &mut Vec<Extent>, extent: Extent) {
+    if extent.length == 0 {
+        return;
+    }
+    if let Some(last) = extents.last_mut() {
+        if last.hole == extent.hole && last.zero == extent.zero {
+            last.length += extent.length;
+            return;
+        }
+    }
+    extents.push(extent);
 }
 
 pub struct AlignedBlockIter {
@@ -176,6 +114,31 @@ pub fn human_size_to_usize(size_str: &str) -> Result<usize, Box<dyn std::error::
     return Err("unreachable".into());
 }
 
+fn termination_signals() -> libc::sigset_t {
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        set
+    }
+}
+
+// Blocks SIGINT and SIGTERM in the calling thread and the threads it spawns afterwards, so they
+// don't kill the process and can be received with `wait_termination_signal` instead. Has to be
+// called before any thread is started.
+pub fn block_termination_signals() {
+    let set = termination_signals();
+    unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) };
+}
+
+pub fn wait_termination_signal() -> i32 {
+    let set = termination_signals();
+    let mut signal: libc::c_int = 0;
+    unsafe { libc::sigwait(&set, &mut signal) };
+    signal
+}
+
 #[allow(unused_imports)]
 #[cfg(test)]
 pub mod test_utils {
@@ -210,7 +173,7 @@ pub mod test_utils {
 
     impl Drop for TempFolder {
         fn drop(&mut self) {
-            remove_dir_all(self.path.clone());
+            let _ = remove_dir_all(self.path.clone());
         }
     }
 }